@@ -0,0 +1,185 @@
+use crate::identity::Identity;
+use crate::proto::access_control::access_control_service_client::AccessControlServiceClient;
+use crate::proto::access_control::response::Decision;
+use crate::proto::access_control::{Context, Request as AccessRequest};
+use crate::proto::attribute::Attribute;
+use crate::proto::auth::Subject;
+use crate::proto::pdf_rendering::UploadOptions;
+use crate::proto::rule::Target;
+use crate::s3::{create_metadata, Resource};
+use config::Config;
+use log::error;
+use prost_wkt_types::Any;
+use serde::Serialize;
+use std::sync::Arc;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Status;
+
+pub struct Auth {
+    config: Config,
+    identity: Arc<Identity>,
+    // Only set up if authorization is enabled, connecting on first use
+    acs: Option<AccessControlServiceClient<Channel>>,
+}
+
+impl Auth {
+    pub fn new(config: Config, identity: Arc<Identity>) -> Self {
+        let acs = config
+            .get_bool("authorization.enabled")
+            .unwrap_or(false)
+            .then(|| {
+                let address = config
+                    .get_string("client.acs.address")
+                    .expect("missing client.acs.address");
+
+                let channel = Endpoint::from_shared(address.clone())
+                    .unwrap_or_else(|err| {
+                        panic!(
+                            "invalid access control service address {}: {}",
+                            address, err
+                        )
+                    })
+                    .connect_lazy();
+
+                AccessControlServiceClient::new(channel)
+            });
+
+        Auth {
+            config,
+            identity,
+            acs,
+        }
+    }
+
+    pub fn required(&self) -> bool {
+        self.config
+            .get_bool("authentication.required")
+            .unwrap_or(false)
+    }
+
+    // Resolves the token of the subject to its user, the returned subject carries the ID
+    // of that user instead of the one claimed by the caller. Subjects without a token
+    // are dropped.
+    pub async fn authenticate(&self, subject: Option<Subject>) -> Result<Option<Subject>, Status> {
+        let token = subject
+            .as_ref()
+            .and_then(|s| s.token.clone())
+            .filter(|t| !t.is_empty());
+
+        let Some(token) = token else {
+            if self.required() {
+                return Err(Status::unauthenticated("missing subject token"));
+            }
+
+            // Without a token nothing the caller claims about itself can be trusted
+            return Ok(None);
+        };
+
+        match self.identity.find_user_id(&token).await? {
+            Some(id) => Ok(subject.map(|s| Subject { id: Some(id), ..s })),
+            None => Err(Status::unauthenticated("invalid subject token")),
+        }
+    }
+
+    // Checks with the access control service that the subject may create the objects
+    // the documents are uploaded to
+    pub async fn authorize(
+        &self,
+        subject: Option<&Subject>,
+        uploads: &[UploadOptions],
+    ) -> Result<(), Status> {
+        let Some(mut client) = self.acs.clone() else {
+            return Ok(());
+        };
+
+        if uploads.is_empty() {
+            return Ok(());
+        }
+
+        if subject.and_then(|s| s.id.as_ref()).is_none() {
+            return Err(Status::unauthenticated(
+                "uploads require an authenticated subject",
+            ));
+        }
+
+        for upload in uploads {
+            let response = client
+                .is_allowed(self.access_request(subject, upload))
+                .await
+                .map_err(|err| {
+                    error!("failed checking access: {}", err);
+                    Status::unavailable("access control service unavailable")
+                })?;
+
+            if response.get_ref().decision() != Decision::Permit {
+                return Err(Status::permission_denied(format!(
+                    "not allowed to create objects in bucket {}",
+                    upload.bucket.clone().unwrap_or_default()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn access_request(&self, subject: Option<&Subject>, upload: &UploadOptions) -> AccessRequest {
+        let urn = |name: &str| {
+            self.config
+                .get_string(&format!("authorization.urns.{}", name))
+                .unwrap_or_default()
+        };
+
+        let bucket = upload.bucket.clone().unwrap_or_default();
+        let key = upload.key.clone().unwrap_or_default();
+
+        // The owners of the object are derived from the scope of the subject
+        let resource = Resource {
+            id: format!("{}/{}", bucket, key),
+            key,
+            bucket,
+            meta: create_metadata(self.config.clone(), subject.cloned()),
+        };
+
+        let target = Target {
+            subjects: vec![attribute(
+                urn("subjectID"),
+                subject.and_then(|s| s.id.clone()).unwrap_or_default(),
+            )],
+            resources: vec![
+                attribute(
+                    urn("entity"),
+                    self.config
+                        .get_string("authorization.resource")
+                        .unwrap_or_default(),
+                ),
+                attribute(urn("resourceID"), resource.id.clone()),
+            ],
+            actions: vec![attribute(urn("actionID"), urn("create"))],
+        };
+
+        AccessRequest {
+            target: Some(target),
+            context: Some(Context {
+                subject: Some(to_any(&subject)),
+                resources: vec![to_any(&resource)],
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+fn attribute(id: String, value: String) -> Attribute {
+    Attribute {
+        id: Some(id),
+        value: Some(value),
+        attributes: Vec::new(),
+    }
+}
+
+// The access control service expects context objects as JSON
+fn to_any<T: Serialize>(value: &T) -> Any {
+    Any {
+        type_url: String::new(),
+        value: serde_json::to_vec(value).unwrap_or_default(),
+    }
+}
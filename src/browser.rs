@@ -0,0 +1,437 @@
+use crate::egress;
+use crate::policy::UrlPolicy;
+use crate::proxy::Proxy;
+use crate::types::RenderError;
+use anyhow::{anyhow, Result};
+use config::Config;
+use headless_chrome::browser::default_executable;
+use headless_chrome::browser::transport::Transport;
+use headless_chrome::protocol::cdp::Target::DisposeBrowserContext;
+use headless_chrome::{Browser, LaunchOptionsBuilder};
+use log::{error, info, warn};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+pub struct Instance {
+    pub browser: Browser,
+    call_timeout: Duration,
+    renders: AtomicUsize,
+}
+
+impl Instance {
+    // Browser level calls which `Browser` doesn't expose go through a second connection
+    pub fn dispose_context(&self, id: String) -> Result<()> {
+        let control = Transport::new(
+            Url::parse(&self.browser.get_ws_url())?,
+            self.browser.get_process_id(),
+            self.call_timeout,
+            None,
+        )?;
+
+        let out = control
+            .call_method_on_browser(DisposeBrowserContext {
+                browser_context_id: id,
+            })
+            .map(|_| ());
+        control.shutdown();
+
+        out
+    }
+}
+
+struct Slot {
+    name: String,
+    proxy: Option<Proxy>,
+    // Dedicated browsers serve documents with their own proxy and are closed once idle
+    dedicated: bool,
+    instance: Mutex<Option<Arc<Instance>>>,
+    active: AtomicUsize,
+}
+
+impl Slot {
+    fn new(name: String, proxy: Option<Proxy>, dedicated: bool) -> Self {
+        Slot {
+            name,
+            proxy,
+            dedicated,
+            instance: Mutex::new(None),
+            active: AtomicUsize::new(0),
+        }
+    }
+}
+
+// Counts towards the tabs of a slot from the start of an acquisition until dropped
+struct Active(Arc<Slot>);
+
+impl Active {
+    fn new(slot: Arc<Slot>) -> Self {
+        slot.active.fetch_add(1, Ordering::SeqCst);
+        Active(slot)
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct Lease {
+    active: Active,
+    pub instance: Arc<Instance>,
+}
+
+// Keeps a pool of browsers running, relaunching them when their connection is lost and
+// recycling them after a number of renders or once they use too much memory.
+// A recycled browser gets no new tabs and exits once its in-flight tabs are done.
+// Readiness is reported as NOT_SERVING while no pooled browser is available.
+pub struct BrowserPool {
+    restart_delay: Duration,
+    call_timeout: Duration,
+    health_timeout: Duration,
+    launch_timeout: Duration,
+    launch_attempts: usize,
+    max_renders: usize,
+    max_rss: u64,
+    proxy: Option<Proxy>,
+    // Browsers without a proxy connect through the egress proxy enforcing the URL policy
+    egress: Proxy,
+    slots: Vec<Arc<Slot>>,
+    dedicated: std::sync::Mutex<HashMap<Option<Proxy>, Arc<Slot>>>,
+    max_dedicated: usize,
+    running: AtomicUsize,
+    health: HealthReporter,
+}
+
+impl BrowserPool {
+    pub fn new(config: &Config, health: HealthReporter) -> Self {
+        let count = config.get_int("renderer.browser.count").unwrap_or(2).max(1) as usize;
+        let proxy = Proxy::from_config(config).map(|p| p.endpoint());
+        let egress = egress::start(UrlPolicy::from_config(config))
+            .unwrap_or_else(|err| panic!("failed starting egress proxy: {}", err));
+
+        BrowserPool {
+            restart_delay: Duration::from_millis(
+                config
+                    .get_int("renderer.browser.restart_delay")
+                    .unwrap_or(1000) as u64,
+            ),
+            call_timeout: Duration::from_millis(
+                config
+                    .get_int("renderer.browser.call_timeout")
+                    .unwrap_or(120000) as u64,
+            ),
+            health_timeout: Duration::from_millis(
+                config
+                    .get_int("renderer.browser.health_timeout")
+                    .unwrap_or(5000) as u64,
+            ),
+            launch_timeout: Duration::from_millis(
+                config
+                    .get_int("renderer.browser.launch_timeout")
+                    .unwrap_or(30000) as u64,
+            ),
+            launch_attempts: config
+                .get_int("renderer.browser.launch_attempts")
+                .unwrap_or(3)
+                .max(1) as usize,
+            max_renders: config
+                .get_int("renderer.browser.max_renders")
+                .unwrap_or(1000) as usize,
+            max_rss: config.get_int("renderer.browser.max_rss").unwrap_or(2048) as u64 * 1024,
+            slots: (0..count)
+                .map(|i| Arc::new(Slot::new(i.to_string(), proxy.clone(), false)))
+                .collect(),
+            proxy,
+            egress: Proxy {
+                server: format!("http://{}", egress),
+                ..Default::default()
+            },
+            dedicated: std::sync::Mutex::new(HashMap::new()),
+            max_dedicated: config.get_int("proxy.max_browsers").unwrap_or(4).max(0) as usize,
+            running: AtomicUsize::new(0),
+            health,
+        }
+    }
+
+    // Routes to the pooled browser with the least tabs open, or to a dedicated browser
+    // if the document uses another proxy than the configured one
+    pub async fn acquire(&self, proxy: Option<&Proxy>) -> Result<Lease, RenderError> {
+        let proxy = proxy.map(|p| p.endpoint());
+
+        let slot = if proxy == self.proxy {
+            let slot = self
+                .slots
+                .iter()
+                .min_by_key(|s| s.active.load(Ordering::SeqCst))
+                .expect("browser pool is empty")
+                .clone();
+            Active::new(slot)
+        } else {
+            let mut dedicated = self.dedicated.lock().unwrap();
+
+            if !dedicated.contains_key(&proxy) && dedicated.len() >= self.max_dedicated {
+                return Err(RenderError::Unavailable(format!(
+                    "too many proxy browsers running, at most {} are allowed",
+                    self.max_dedicated
+                )));
+            }
+
+            let slot = dedicated
+                .entry(proxy.clone())
+                .or_insert_with(|| {
+                    let name = match &proxy {
+                        Some(proxy) => format!("for proxy {}", proxy.server),
+                        None => "without proxy".to_string(),
+                    };
+                    Arc::new(Slot::new(name, proxy.clone(), true))
+                })
+                .clone();
+            Active::new(slot)
+        };
+
+        Ok(Lease {
+            instance: self.instance(&slot.0).await?,
+            active: slot,
+        })
+    }
+
+    pub async fn release(&self, lease: Lease) {
+        let slot = &lease.active.0;
+
+        let renders = lease.instance.renders.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.max_renders > 0 && renders >= self.max_renders {
+            info!("recycling browser {} after {} renders", slot.name, renders);
+            self.retire(slot, &lease.instance).await;
+        }
+    }
+
+    pub async fn restart(&self, lease: Lease) {
+        let slot = &lease.active.0;
+
+        warn!("browser {} connection lost, restarting", slot.name);
+        self.retire(slot, &lease.instance).await;
+    }
+
+    pub fn monitor(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                for slot in &self.slots {
+                    if let Ok(instance) = self.instance(slot).await {
+                        self.check(slot, &instance).await;
+                    }
+                }
+
+                // Dedicated browsers without open tabs are closed, the others are checked
+                let dedicated: Vec<Arc<Slot>> = {
+                    let mut dedicated = self.dedicated.lock().unwrap();
+                    dedicated.retain(|_, slot| slot.active.load(Ordering::SeqCst) > 0);
+                    dedicated.values().cloned().collect()
+                };
+
+                for slot in dedicated {
+                    let instance = slot.instance.lock().await.clone();
+                    if let Some(instance) = instance {
+                        self.check(&slot, &instance).await;
+                    }
+                }
+
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+
+    async fn check(&self, slot: &Arc<Slot>, instance: &Arc<Instance>) {
+        if !self.is_alive(instance).await {
+            warn!("browser {} connection lost, restarting", slot.name);
+            self.retire(slot, instance).await;
+            return;
+        }
+
+        if self.max_rss == 0 {
+            return;
+        }
+
+        let Some(pid) = instance.browser.get_process_id() else {
+            return;
+        };
+
+        let rss = tokio::task::spawn_blocking(move || process_tree_rss(pid))
+            .await
+            .unwrap_or_default();
+
+        if rss > self.max_rss {
+            info!("recycling browser {} using {} KiB", slot.name, rss);
+            self.retire(slot, instance).await;
+        }
+    }
+
+    // Probes the browser connection, a browser not answering in time counts as crashed
+    pub async fn is_alive(&self, instance: &Arc<Instance>) -> bool {
+        let instance = instance.clone();
+
+        tokio::time::timeout(
+            self.health_timeout,
+            tokio::task::spawn_blocking(move || instance.browser.get_version().is_ok()),
+        )
+        .await
+        .is_ok_and(|alive| alive.unwrap_or(false))
+    }
+
+    async fn instance(&self, slot: &Arc<Slot>) -> Result<Arc<Instance>, RenderError> {
+        let mut instance = slot.instance.lock().await;
+        let mut attempts = 0;
+
+        loop {
+            if let Some(instance) = instance.as_ref() {
+                return Ok(instance.clone());
+            }
+
+            let proxy = slot.proxy.clone().unwrap_or_else(|| self.egress.clone());
+            let call_timeout = self.call_timeout;
+            let launched = tokio::time::timeout(
+                self.launch_timeout,
+                tokio::task::spawn_blocking(move || launch(proxy, call_timeout)),
+            )
+            .await
+            .map_err(|_| anyhow!("timed out after {:?}", self.launch_timeout))
+            .and_then(|joined| joined.map_err(|err| anyhow!(err)))
+            .and_then(|launched| launched);
+
+            match launched {
+                Ok(launched) => {
+                    info!("browser {} launched", slot.name);
+                    *instance = Some(Arc::new(launched));
+
+                    if !slot.dedicated {
+                        self.running.fetch_add(1, Ordering::SeqCst);
+                        self.update_readiness().await;
+                    }
+                }
+                Err(err) => {
+                    error!("failed launching browser {}: {}", slot.name, err);
+                    self.update_readiness().await;
+
+                    attempts += 1;
+                    if attempts >= self.launch_attempts {
+                        return Err(RenderError::Unavailable(format!(
+                            "browser {} could not be launched: {}",
+                            slot.name, err
+                        )));
+                    }
+
+                    tokio::time::sleep(self.restart_delay).await;
+                }
+            }
+        }
+    }
+
+    // Removes the browser from its slot, it is closed once the last lease is dropped
+    async fn retire(&self, slot: &Arc<Slot>, retired: &Arc<Instance>) {
+        let mut instance = slot.instance.lock().await;
+
+        if instance.as_ref().is_some_and(|i| Arc::ptr_eq(i, retired)) {
+            *instance = None;
+
+            if !slot.dedicated {
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                self.update_readiness().await;
+            }
+        }
+    }
+
+    async fn update_readiness(&self) {
+        let status = if self.running.load(Ordering::SeqCst) > 0 {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+
+        self.health.set_service_status("readiness", status).await;
+    }
+}
+
+// The call timeout also closes connections idle for as long, which the health checks prevent
+fn launch(proxy: Proxy, call_timeout: Duration) -> Result<Instance> {
+    let args: Vec<OsString> = proxy
+        .chrome_args()
+        .into_iter()
+        .map(OsString::from)
+        .collect();
+
+    let options = LaunchOptionsBuilder::default()
+        .path(Some(default_executable().map_err(|e| anyhow!(e))?))
+        .sandbox(false)
+        .idle_browser_timeout(call_timeout)
+        .args(args.iter().map(|a| a.as_os_str()).collect())
+        .build()
+        .map_err(|e| anyhow!(e))?;
+
+    Ok(Instance {
+        browser: Browser::new(options)?,
+        call_timeout,
+        renders: AtomicUsize::new(0),
+    })
+}
+
+// Sums the resident memory in KiB of a process and all of its descendants,
+// as Chrome spreads tabs over renderer child processes
+fn process_tree_rss(pid: u32) -> u64 {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return 0;
+    };
+
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+
+    for entry in entries.flatten() {
+        let Some(child) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<u32>().ok())
+        else {
+            continue;
+        };
+
+        // The parent PID is the second field after the parenthesized command name
+        let parent = std::fs::read_to_string(format!("/proc/{}/stat", child))
+            .ok()
+            .and_then(|stat| {
+                stat.rsplit_once(')')
+                    .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+                    .and_then(|ppid| ppid.parse::<u32>().ok())
+            });
+
+        if let Some(parent) = parent {
+            children.entry(parent).or_default().push(child);
+        }
+    }
+
+    let mut total = 0;
+    let mut pending = vec![pid];
+
+    while let Some(pid) = pending.pop() {
+        total += std::fs::read_to_string(format!("/proc/{}/status", pid))
+            .ok()
+            .and_then(|status| {
+                status
+                    .lines()
+                    .find(|line| line.starts_with("VmRSS:"))
+                    .and_then(|line| line.split_whitespace().nth(1))
+                    .and_then(|kb| kb.parse::<u64>().ok())
+            })
+            .unwrap_or_default();
+
+        if let Some(c) = children.get(&pid) {
+            pending.extend(c);
+        }
+    }
+
+    total
+}
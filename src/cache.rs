@@ -0,0 +1,271 @@
+use crate::proto::pdf_rendering::RenderData;
+use crate::types::Assets;
+use config::Config;
+use log::error;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+#[tonic::async_trait]
+pub trait RenderCache: Send + Sync {
+    async fn get(&self, key: &str) -> Option<Vec<u8>>;
+    async fn put(&self, key: &str, data: Vec<u8>);
+}
+
+pub fn create_cache(config: &Config) -> Option<Arc<dyn RenderCache>> {
+    let ttl = Duration::from_secs(config.get_int("cache.ttl").unwrap_or(3600) as u64);
+    let max_size = config.get_int("cache.max_size").unwrap_or(268435456) as u64;
+
+    match config
+        .get_string("cache.backend")
+        .unwrap_or("none".to_string())
+        .as_str()
+    {
+        "none" => None,
+        "memory" => Some(Arc::new(MemoryCache::new(ttl, max_size))),
+        "file" => Some(Arc::new(FileCache::new(
+            PathBuf::from(
+                config
+                    .get_string("cache.file.path")
+                    .expect("missing cache.file.path"),
+            ),
+            ttl,
+            max_size,
+            Duration::from_millis(
+                config.get_int("cache.file.prune_interval").unwrap_or(60000) as u64
+            ),
+        ))),
+        other => panic!("unknown cache backend: {}", other),
+    }
+}
+
+// Hashes everything the rendered PDF depends on, which is the document with its
+// options and any uploaded assets
+pub fn cache_key(data: &RenderData, assets: &Assets) -> Option<String> {
+    let mut hasher = Sha256::new();
+
+    let document = serde_json::to_vec(data).ok()?;
+    hasher.update((document.len() as u64).to_le_bytes());
+    hasher.update(document);
+
+    let mut paths: Vec<&String> = assets.keys().collect();
+    paths.sort();
+
+    for path in paths {
+        let asset = &assets[path];
+        for part in [path.as_bytes(), asset.content_type.as_bytes(), &asset.data] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+    }
+
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+struct MemoryEntry {
+    data: Vec<u8>,
+    created: Instant,
+    used: u64,
+}
+
+struct Lru {
+    entries: HashMap<String, MemoryEntry>,
+    // Keys by the tick they were last used at, the first entry is evicted first
+    order: BTreeMap<u64, String>,
+    size: u64,
+    tick: u64,
+}
+
+impl Lru {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.size -= entry.data.len() as u64;
+        }
+    }
+}
+
+pub struct MemoryCache {
+    ttl: Duration,
+    max_size: u64,
+    lru: Mutex<Lru>,
+}
+
+impl MemoryCache {
+    pub fn new(ttl: Duration, max_size: u64) -> Self {
+        MemoryCache {
+            ttl,
+            max_size,
+            lru: Mutex::new(Lru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                size: 0,
+                tick: 0,
+            }),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl RenderCache for MemoryCache {
+    async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut lru = self.lru.lock().unwrap();
+
+        if lru.entries.get(key)?.created.elapsed() >= self.ttl {
+            lru.remove(key);
+            return None;
+        }
+
+        lru.tick += 1;
+        let tick = lru.tick;
+
+        let entry = lru.entries.get_mut(key)?;
+        let used = std::mem::replace(&mut entry.used, tick);
+        let data = entry.data.clone();
+
+        lru.order.remove(&used);
+        lru.order.insert(tick, key.to_string());
+
+        Some(data)
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) {
+        if data.len() as u64 > self.max_size {
+            return;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove(key);
+
+        lru.tick += 1;
+        let tick = lru.tick;
+
+        lru.size += data.len() as u64;
+        lru.order.insert(tick, key.to_string());
+        lru.entries.insert(
+            key.to_string(),
+            MemoryEntry {
+                data,
+                created: Instant::now(),
+                used: tick,
+            },
+        );
+
+        while lru.size > self.max_size {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+
+            if let Some(entry) = lru.entries.remove(&oldest) {
+                lru.size -= entry.data.len() as u64;
+            }
+        }
+    }
+}
+
+pub struct FileCache {
+    path: PathBuf,
+    ttl: Duration,
+    max_size: u64,
+}
+
+impl FileCache {
+    // Expired and excess entries are removed periodically rather than on every write
+    pub fn new(path: PathBuf, ttl: Duration, max_size: u64, prune_interval: Duration) -> Self {
+        std::fs::create_dir_all(&path)
+            .unwrap_or_else(|err| panic!("failed creating cache directory {:?}: {}", path, err));
+
+        let dir = path.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(prune_interval).await;
+
+                let dir = dir.clone();
+                let _ = tokio::task::spawn_blocking(move || prune(&dir, ttl, max_size)).await;
+            }
+        });
+
+        FileCache {
+            path,
+            ttl,
+            max_size,
+        }
+    }
+
+    fn entry_path(&self, key: &str) -> Option<PathBuf> {
+        // Keys are hex encoded hashes, anything else must not be used as a file name
+        key.chars()
+            .all(|c| c.is_ascii_hexdigit())
+            .then(|| self.path.join(format!("{}.pdf", key)))
+    }
+}
+
+#[tonic::async_trait]
+impl RenderCache for FileCache {
+    async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.entry_path(key)?;
+        let modified = tokio::fs::metadata(&path).await.ok()?.modified().ok()?;
+
+        if modified.elapsed().unwrap_or_default() >= self.ttl {
+            let _ = tokio::fs::remove_file(&path).await;
+            return None;
+        }
+
+        tokio::fs::read(&path).await.ok()
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) {
+        let Some(path) = self.entry_path(key) else {
+            return;
+        };
+
+        if data.len() as u64 > self.max_size {
+            return;
+        }
+
+        // Written to a temporary file first, so readers never see partial entries
+        let temporary = path.with_extension(format!("{}.tmp", ulid::Ulid::new()));
+        let written = match tokio::fs::write(&temporary, data).await {
+            Ok(_) => tokio::fs::rename(&temporary, &path).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = written {
+            error!("failed writing cache entry {}: {}", key, err);
+            let _ = tokio::fs::remove_file(&temporary).await;
+        }
+    }
+}
+
+// Removes expired entries and then the oldest ones until the cache fits its size limit
+fn prune(dir: &Path, ttl: Duration, max_size: u64) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+        .flatten()
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "pdf"))
+        .filter_map(|e| {
+            let metadata = e.metadata().ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), e.path()))
+        })
+        .collect();
+
+    files.sort();
+
+    let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+
+    for (modified, len, path) in files {
+        let expired = modified.elapsed().unwrap_or_default() >= ttl;
+        if !expired && size <= max_size {
+            continue;
+        }
+
+        if std::fs::remove_file(&path).is_ok() {
+            size -= len;
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::policy::UrlPolicy;
+use log::{debug, warn};
+use reqwest::Url;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const MAX_HEAD_SIZE: usize = 65536;
+
+// A forward proxy for browsers without a configured proxy. Each connection is checked
+// against the URL policy and made to the addresses which were checked, so a host can't
+// resolve to an allowed address for the check and to another one for the browser.
+// It also covers connections the browser doesn't intercept, like those of workers.
+pub fn start(policy: UrlPolicy) -> io::Result<SocketAddr> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    let listener = TcpListener::from_std(listener)?;
+    let policy = Arc::new(policy);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let policy = policy.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = serve(policy, stream).await {
+                            debug!("egress connection failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => warn!("failed accepting egress connection: {}", err),
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn serve(policy: Arc<UrlPolicy>, mut client: TcpStream) -> io::Result<()> {
+    let mut head = Vec::new();
+    let end = loop {
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+
+        if head.len() > MAX_HEAD_SIZE {
+            return respond(&mut client, "431 Request Header Fields Too Large").await;
+        }
+
+        let mut buf = [0; 4096];
+        let read = client.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..read]);
+    };
+
+    let body = head.split_off(end);
+    let mut lines = head
+        .split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+
+    let request_line = String::from_utf8_lossy(lines.next().unwrap_or_default()).into_owned();
+    let mut request = request_line.split(' ');
+    let (method, target, version) = (
+        request.next().unwrap_or_default(),
+        request.next().unwrap_or_default(),
+        request.next().unwrap_or("HTTP/1.1"),
+    );
+
+    // Tunnels carry TLS or WebSockets, only their host is known
+    let connect = method.eq_ignore_ascii_case("CONNECT");
+    let url = if connect {
+        format!("https://{}/", target)
+    } else {
+        target.to_string()
+    };
+
+    let resolved = tokio::task::spawn_blocking({
+        let url = url.clone();
+        move || policy.resolve(&url)
+    })
+    .await
+    .map_err(io::Error::other)?;
+
+    let addresses = match resolved {
+        Ok(addresses) => addresses,
+        Err(reason) => {
+            warn!("blocked connection to {}: {}", url, reason);
+            return respond(&mut client, "403 Forbidden").await;
+        }
+    };
+
+    let mut upstream = match TcpStream::connect(addresses.as_slice()).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            debug!("failed connecting to {}: {}", url, err);
+            return respond(&mut client, "502 Bad Gateway").await;
+        }
+    };
+
+    if connect {
+        client
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+    } else {
+        // Requests are forwarded in origin form and the connection is closed after the
+        // response, so the browser can't reuse it for a request to another host
+        let Ok(target) = Url::parse(target) else {
+            return respond(&mut client, "400 Bad Request").await;
+        };
+
+        let mut forwarded = match target.query() {
+            Some(query) => format!("{} {}?{} {}\r\n", method, target.path(), query, version),
+            None => format!("{} {} {}\r\n", method, target.path(), version),
+        }
+        .into_bytes();
+
+        for line in lines.filter(|line| !line.is_empty()) {
+            let name = line.split(|b| *b == b':').next().unwrap_or_default();
+            let hop_by_hop = [
+                "connection",
+                "keep-alive",
+                "proxy-connection",
+                "proxy-authorization",
+            ]
+            .iter()
+            .any(|header| name.trim_ascii().eq_ignore_ascii_case(header.as_bytes()));
+
+            if !hop_by_hop {
+                forwarded.extend_from_slice(line);
+                forwarded.extend_from_slice(b"\r\n");
+            }
+        }
+
+        forwarded.extend_from_slice(b"Connection: close\r\n\r\n");
+        upstream.write_all(&forwarded).await?;
+    }
+
+    upstream.write_all(&body).await?;
+    copy_bidirectional(&mut client, &mut upstream).await?;
+
+    Ok(())
+}
+
+async fn respond(client: &mut TcpStream, status: &str) -> io::Result<()> {
+    client
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .as_bytes(),
+        )
+        .await
+}
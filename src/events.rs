@@ -0,0 +1,219 @@
+use config::Config;
+use log::{error, warn};
+use serde::Serialize;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    RenderCompleted {
+        request_id: String,
+        index: usize,
+        size: usize,
+        page_count: Option<usize>,
+        subject_id: Option<String>,
+    },
+    RenderFailed {
+        request_id: String,
+        index: usize,
+        error: String,
+        subject_id: Option<String>,
+    },
+    ObjectUploaded {
+        request_id: String,
+        index: usize,
+        bucket: String,
+        key: String,
+        url: String,
+        size: usize,
+        page_count: Option<usize>,
+        subject_id: Option<String>,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::RenderCompleted { .. } => "RenderCompleted",
+            Event::RenderFailed { .. } => "RenderFailed",
+            Event::ObjectUploaded { .. } => "ObjectUploaded",
+        }
+    }
+}
+
+#[tonic::async_trait]
+pub trait EventSink: Send + Sync {
+    async fn emit(&self, event: Event);
+}
+
+pub fn create_event_sink(config: &Config) -> Arc<dyn EventSink> {
+    let sink: Arc<dyn EventSink> = match config
+        .get_string("events.sink")
+        .unwrap_or("none".to_string())
+        .as_str()
+    {
+        "none" => return Arc::new(NoopSink {}),
+        "stdout" => Arc::new(StdoutSink {}),
+        "file" => Arc::new(FileSink::new(
+            config
+                .get_string("events.file.path")
+                .expect("missing events.file.path"),
+        )),
+        #[cfg(feature = "kafka")]
+        "kafka" => Arc::new(KafkaSink::new(config)),
+        other => panic!("unknown event sink: {}", other),
+    };
+
+    Arc::new(QueuedSink::new(
+        sink,
+        config.get_int("events.queue_size").unwrap_or(1024).max(1) as usize,
+    ))
+}
+
+// Hands events to a background task so a slow sink never holds up a render,
+// events are dropped while the queue is full
+pub struct QueuedSink {
+    queue: mpsc::Sender<Event>,
+}
+
+impl QueuedSink {
+    pub fn new(sink: Arc<dyn EventSink>, size: usize) -> Self {
+        let (queue, mut rx) = mpsc::channel::<Event>(size);
+
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                sink.emit(event).await;
+            }
+        });
+
+        QueuedSink { queue }
+    }
+}
+
+#[tonic::async_trait]
+impl EventSink for QueuedSink {
+    async fn emit(&self, event: Event) {
+        match self.queue.try_send(event) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(event)) => {
+                warn!("event queue is full, dropping {} event", event.name())
+            }
+            Err(mpsc::error::TrySendError::Closed(event)) => {
+                error!("event queue is closed, dropping {} event", event.name())
+            }
+        }
+    }
+}
+
+pub struct NoopSink {}
+
+#[tonic::async_trait]
+impl EventSink for NoopSink {
+    async fn emit(&self, _: Event) {}
+}
+
+pub struct StdoutSink {}
+
+#[tonic::async_trait]
+impl EventSink for StdoutSink {
+    async fn emit(&self, event: Event) {
+        match serde_json::to_string(&event) {
+            Ok(json) => println!("{}", json),
+            Err(err) => error!("failed serializing {} event: {}", event.name(), err),
+        }
+    }
+}
+
+pub struct FileSink {
+    file: Mutex<std::fs::File>,
+}
+
+impl FileSink {
+    pub fn new(path: String) -> Self {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap_or_else(|err| panic!("failed opening event file {}: {}", path, err));
+
+        FileSink {
+            file: Mutex::new(file),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl EventSink for FileSink {
+    async fn emit(&self, event: Event) {
+        let json = match serde_json::to_string(&event) {
+            Ok(json) => json,
+            Err(err) => {
+                error!("failed serializing {} event: {}", event.name(), err);
+                return;
+            }
+        };
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(err) = writeln!(file, "{}", json) {
+            error!("failed writing {} event: {}", event.name(), err);
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub struct KafkaSink {
+    producer: rdkafka::producer::FutureProducer,
+    topic: String,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaSink {
+    pub fn new(config: &Config) -> Self {
+        let producer = rdkafka::ClientConfig::new()
+            .set(
+                "bootstrap.servers",
+                config
+                    .get_string("events.kafka.brokers")
+                    .expect("missing events.kafka.brokers"),
+            )
+            .set("message.timeout.ms", "5000")
+            .create()
+            .expect("failed creating kafka producer");
+
+        KafkaSink {
+            producer,
+            topic: config
+                .get_string("events.kafka.topic")
+                .unwrap_or("io.restorecommerce.rendering".to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[tonic::async_trait]
+impl EventSink for KafkaSink {
+    async fn emit(&self, event: Event) {
+        let json = match serde_json::to_string(&event) {
+            Ok(json) => json,
+            Err(err) => {
+                error!("failed serializing {} event: {}", event.name(), err);
+                return;
+            }
+        };
+
+        let record = rdkafka::producer::FutureRecord::to(&self.topic)
+            .key(event.name())
+            .payload(&json);
+
+        match self
+            .producer
+            .send(record, rdkafka::util::Timeout::Never)
+            .await
+        {
+            Ok(_) => log::info!("emitted {} event", event.name()),
+            Err((err, _)) => error!("failed emitting {} event: {}", event.name(), err),
+        }
+    }
+}
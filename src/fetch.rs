@@ -0,0 +1,113 @@
+use crate::policy::UrlPolicy;
+use crate::proxy::Proxy;
+use crate::types::RenderError;
+use anyhow::{anyhow, Result};
+use config::Config;
+use reqwest::header::LOCATION;
+use reqwest::{redirect, Url};
+use std::time::Duration;
+
+pub async fn fetch_template(config: &Config, url: &str, proxy: Option<&Proxy>) -> Result<String> {
+    let timeout = Duration::from_millis(
+        config
+            .get_int("renderer.templates.timeout")
+            .unwrap_or(10000) as u64,
+    );
+    let max_size = config
+        .get_int("renderer.templates.max_size")
+        .unwrap_or(1048576) as usize;
+
+    let policy = UrlPolicy::from_config(config);
+
+    let mut location = Url::parse(url)?;
+    let mut redirects = 0;
+
+    // Redirects are followed here, so that each of them is checked against the policy
+    // without resolving hosts on the runtime
+    let mut response = loop {
+        let client = client(&policy, &location, proxy, timeout).await?;
+        let response = client.get(location.clone()).send().await?;
+
+        let next = response
+            .status()
+            .is_redirection()
+            .then(|| response.headers().get(LOCATION))
+            .flatten()
+            .and_then(|next| next.to_str().ok());
+
+        let Some(next) = next else {
+            break response.error_for_status()?;
+        };
+
+        redirects += 1;
+        if redirects > 10 {
+            return Err(anyhow!("template {} redirected too many times", url));
+        }
+
+        location = location.join(next)?;
+    };
+
+    if let Some(length) = response.content_length() {
+        if length as usize > max_size {
+            return Err(anyhow!(
+                "template {} exceeds size limit of {} bytes",
+                url,
+                max_size
+            ));
+        }
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_size {
+            return Err(anyhow!(
+                "template {} exceeds size limit of {} bytes",
+                url,
+                max_size
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(String::from_utf8(body)?)
+}
+
+// Clients without a proxy connect to the addresses which were checked, so that the host
+// can't resolve to another address when connecting
+async fn client(
+    policy: &UrlPolicy,
+    url: &Url,
+    proxy: Option<&Proxy>,
+    timeout: Duration,
+) -> Result<reqwest::Client> {
+    let resolved = tokio::task::spawn_blocking({
+        let (policy, url, pinned) = (policy.clone(), url.to_string(), proxy.is_none());
+        move || {
+            if pinned {
+                policy.resolve(&url)
+            } else {
+                policy.check(&url).map(|_| Vec::new())
+            }
+        }
+    })
+    .await?;
+
+    let addresses = resolved.map_err(|reason| {
+        anyhow!(RenderError::Forbidden(format!(
+            "template {} is not allowed: {}",
+            url, reason
+        )))
+    })?;
+
+    let mut client = reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(redirect::Policy::none());
+
+    client = match (proxy, url.host_str()) {
+        (Some(proxy), _) => client.proxy(proxy.client()?),
+        (None, Some(host)) => client.no_proxy().resolve_to_addrs(host, &addresses),
+        (None, None) => client.no_proxy(),
+    };
+
+    Ok(client.build()?)
+}
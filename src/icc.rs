@@ -0,0 +1,131 @@
+// Builds a minimal ICC v2 display profile describing the sRGB IEC61966-2.1 color space.
+// The profile is used as the destination profile of PDF/A output intents.
+
+const DESCRIPTION: &str = "sRGB IEC61966-2.1";
+const COPYRIGHT: &str = "No copyright, use freely";
+
+// D50 adapted primaries and white point
+const WHITE_POINT: [f64; 3] = [0.9642, 1.0, 0.8249];
+const RED: [f64; 3] = [0.4361, 0.2225, 0.0139];
+const GREEN: [f64; 3] = [0.3851, 0.7169, 0.0971];
+const BLUE: [f64; 3] = [0.1431, 0.0606, 0.7141];
+
+const CURVE_POINTS: usize = 1024;
+
+pub fn srgb_icc_profile() -> Vec<u8> {
+    let curve = curve_tag();
+    let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (b"desc", description_tag(DESCRIPTION)),
+        (b"cprt", text_tag(COPYRIGHT)),
+        (b"wtpt", xyz_tag(WHITE_POINT)),
+        (b"rXYZ", xyz_tag(RED)),
+        (b"gXYZ", xyz_tag(GREEN)),
+        (b"bXYZ", xyz_tag(BLUE)),
+        (b"rTRC", curve.clone()),
+        (b"gTRC", curve.clone()),
+        (b"bTRC", curve),
+    ];
+
+    let table_size = 4 + tags.len() * 12;
+    let mut offset = 128 + table_size;
+
+    let mut table = Vec::with_capacity(table_size);
+    let mut data = Vec::new();
+
+    table.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+    for (signature, tag) in tags.iter() {
+        table.extend_from_slice(*signature);
+        table.extend_from_slice(&(offset as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+
+        data.extend_from_slice(tag);
+        while data.len() % 4 != 0 {
+            data.push(0);
+        }
+        offset = 128 + table_size + data.len();
+    }
+
+    let size = 128 + table.len() + data.len();
+
+    let mut header = Vec::with_capacity(128);
+    header.extend_from_slice(&(size as u32).to_be_bytes());
+    header.extend_from_slice(&[0; 4]); // preferred CMM
+    header.extend_from_slice(&[0x02, 0x10, 0x00, 0x00]); // version 2.1
+    header.extend_from_slice(b"mntr");
+    header.extend_from_slice(b"RGB ");
+    header.extend_from_slice(b"XYZ ");
+    header.extend_from_slice(&[0; 12]); // creation date
+    header.extend_from_slice(b"acsp");
+    header.extend_from_slice(&[0; 4]); // platform
+    header.extend_from_slice(&[0; 4]); // flags
+    header.extend_from_slice(&[0; 4]); // manufacturer
+    header.extend_from_slice(&[0; 4]); // model
+    header.extend_from_slice(&[0; 8]); // attributes
+    header.extend_from_slice(&[0; 4]); // perceptual rendering intent
+    for value in WHITE_POINT {
+        header.extend_from_slice(&s15_fixed16(value));
+    }
+    header.extend_from_slice(&[0; 4]); // creator
+    header.resize(128, 0);
+
+    let mut out = header;
+    out.extend(table);
+    out.extend(data);
+    out
+}
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(20);
+    out.extend_from_slice(b"XYZ ");
+    out.extend_from_slice(&[0; 4]);
+    for value in xyz {
+        out.extend_from_slice(&s15_fixed16(value));
+    }
+    out
+}
+
+fn text_tag(text: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(b"text");
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(text.as_bytes());
+    out.push(0);
+    out
+}
+
+fn description_tag(text: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(b"desc");
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+    out.extend_from_slice(text.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&[0; 4]); // unicode language code
+    out.extend_from_slice(&[0; 4]); // unicode count
+    out.extend_from_slice(&[0; 2]); // scriptcode code
+    out.push(0); // scriptcode count
+    out.extend_from_slice(&[0; 67]);
+    out
+}
+
+// Sampled sRGB transfer function
+fn curve_tag() -> Vec<u8> {
+    let mut out = Vec::with_capacity(12 + CURVE_POINTS * 2);
+    out.extend_from_slice(b"curv");
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(CURVE_POINTS as u32).to_be_bytes());
+    for i in 0..CURVE_POINTS {
+        let v = i as f64 / (CURVE_POINTS - 1) as f64;
+        let linear = if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        };
+        out.extend_from_slice(&((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+    out
+}
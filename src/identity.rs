@@ -0,0 +1,111 @@
+use crate::proto::user::user_service_client::UserServiceClient;
+use crate::proto::user::FindByTokenRequest;
+use config::Config;
+use log::error;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+
+// Resolves subject tokens over a single connection to the identity service, which is
+// established on first use and shared by all requests. Users of known tokens are cached.
+pub struct Identity {
+    client: UserServiceClient<Channel>,
+    ttl: Duration,
+    max_entries: usize,
+    // Users by the hash of their token, with the time they were looked up
+    cache: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Identity {
+    pub fn new(config: &Config) -> Self {
+        let address = config
+            .get_string("client.user.address")
+            .expect("missing client.user.address");
+        let timeout =
+            Duration::from_millis(config.get_int("client.user.timeout").unwrap_or(5000) as u64);
+
+        let channel = Endpoint::from_shared(address.clone())
+            .unwrap_or_else(|err| panic!("invalid identity service address {}: {}", address, err))
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .connect_lazy();
+
+        Identity {
+            client: UserServiceClient::new(channel),
+            ttl: Duration::from_secs(config.get_int("client.user.cache.ttl").unwrap_or(300) as u64),
+            max_entries: config
+                .get_int("client.user.cache.max_entries")
+                .unwrap_or(10000) as usize,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns the ID of the user the token belongs to, or None if the token is unknown
+    pub async fn find_user_id(&self, token: &str) -> Result<Option<String>, Status> {
+        let key = format!("{:x}", Sha256::digest(token.as_bytes()));
+
+        if let Some((id, looked_up)) = self.cache.lock().unwrap().get(&key) {
+            if looked_up.elapsed() < self.ttl {
+                return Ok(Some(id.clone()));
+            }
+        }
+
+        let response = self
+            .client
+            .clone()
+            .find_by_token(FindByTokenRequest {
+                token: Some(token.to_string()),
+            })
+            .await;
+
+        let id = match response {
+            Ok(response) => response.into_inner().payload.and_then(|user| user.id),
+            Err(status)
+                if matches!(
+                    status.code(),
+                    Code::NotFound | Code::Unauthenticated | Code::PermissionDenied
+                ) =>
+            {
+                None
+            }
+            Err(status) => {
+                error!("failed resolving subject token: {}", status);
+                return Err(Status::unavailable("identity service unavailable"));
+            }
+        };
+
+        if let Some(id) = &id {
+            self.remember(key, id.clone());
+        }
+
+        Ok(id)
+    }
+
+    fn remember(&self, key: String, id: String) {
+        if self.ttl.is_zero() || self.max_entries == 0 {
+            return;
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+
+        if cache.len() >= self.max_entries {
+            cache.retain(|_, (_, looked_up)| looked_up.elapsed() < self.ttl);
+        }
+
+        if cache.len() >= self.max_entries {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, (_, looked_up))| *looked_up)
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+
+        cache.insert(key, (id, Instant::now()));
+    }
+}
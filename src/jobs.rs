@@ -0,0 +1,320 @@
+use crate::proto::pdf_rendering::job_response::State;
+use crate::proto::pdf_rendering::{JobResponse, RenderingResponse};
+use config::Config;
+use log::{error, warn};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::{AbortHandle, JoinHandle};
+
+// A job together with the ID of the subject which submitted it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Job {
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(flatten)]
+    pub response: JobResponse,
+}
+
+#[tonic::async_trait]
+pub trait JobStore: Send + Sync {
+    async fn put(&self, job: Job);
+    async fn get(&self, id: &str) -> Option<Job>;
+}
+
+pub fn create_job_store(config: &Config) -> Arc<dyn JobStore> {
+    let ttl = Duration::from_secs(config.get_int("jobs.ttl").unwrap_or(86400) as u64);
+
+    match config
+        .get_string("jobs.store")
+        .unwrap_or("memory".to_string())
+        .as_str()
+    {
+        "memory" => Arc::new(MemoryJobStore {
+            ttl,
+            max_entries: config
+                .get_int("jobs.memory.max_entries")
+                .unwrap_or(10000)
+                .max(1) as usize,
+            max_bytes: config
+                .get_int("jobs.memory.max_bytes")
+                .unwrap_or(1073741824) as usize,
+            jobs: Mutex::new(HashMap::new()),
+        }),
+        "file" => Arc::new(FileJobStore::new(
+            PathBuf::from(
+                config
+                    .get_string("jobs.file.path")
+                    .expect("missing jobs.file.path"),
+            ),
+            ttl,
+        )),
+        other => panic!("unknown job store: {}", other),
+    }
+}
+
+pub struct MemoryJobStore {
+    ttl: Duration,
+    max_entries: usize,
+    max_bytes: usize,
+    jobs: Mutex<HashMap<String, (Instant, Job)>>,
+}
+
+#[tonic::async_trait]
+impl JobStore for MemoryJobStore {
+    async fn put(&self, job: Job) {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        // Expire jobs which haven't been updated within the TTL
+        let now = Instant::now();
+        jobs.retain(|_, (updated, _)| now.duration_since(*updated) < self.ttl);
+
+        let id = job.response.id.clone();
+        jobs.insert(id.clone(), (now, job));
+
+        // Evict the least recently updated jobs while the store is over its limits
+        let mut size: usize = jobs
+            .values()
+            .map(|(_, job)| job.response.encoded_len())
+            .sum();
+
+        while jobs.len() > self.max_entries || size > self.max_bytes {
+            let Some(oldest) = jobs
+                .iter()
+                .filter(|(key, _)| **key != id)
+                .min_by_key(|(_, (updated, _))| *updated)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+
+            if let Some((_, evicted)) = jobs.remove(&oldest) {
+                size -= evicted.response.encoded_len();
+                warn!("job store is full, evicting job {}", oldest);
+            }
+        }
+    }
+
+    async fn get(&self, id: &str) -> Option<Job> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|(updated, _)| updated.elapsed() < self.ttl)
+            .map(|(_, job)| job.clone())
+    }
+}
+
+// Jobs are stored as JSON files, their results as protobuf encoded files next to them
+// so that rendered documents are not serialized as JSON arrays of numbers
+pub struct FileJobStore {
+    path: PathBuf,
+    ttl: Duration,
+    prune: Arc<Mutex<()>>,
+}
+
+impl FileJobStore {
+    pub fn new(path: PathBuf, ttl: Duration) -> Self {
+        std::fs::create_dir_all(&path)
+            .unwrap_or_else(|err| panic!("failed creating job directory {:?}: {}", path, err));
+
+        FileJobStore {
+            path,
+            ttl,
+            prune: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn job_path(&self, id: &str, extension: &str) -> Option<PathBuf> {
+        // Job IDs are ULIDs, anything else must not be used as a file name
+        ulid::Ulid::from_string(id)
+            .ok()
+            .map(|id| self.path.join(format!("{}.{}", id, extension)))
+    }
+}
+
+#[tonic::async_trait]
+impl JobStore for FileJobStore {
+    async fn put(&self, mut job: Job) {
+        let (Some(path), Some(result_path)) = (
+            self.job_path(&job.response.id, "json"),
+            self.job_path(&job.response.id, "result"),
+        ) else {
+            error!("invalid job id: {}", job.response.id);
+            return;
+        };
+
+        // The result is written first, so a job never refers to a missing result
+        let written = match job.response.result.take() {
+            Some(result) => write_file(&result_path, result.encode_to_vec()).await,
+            None => match tokio::fs::remove_file(&result_path).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            },
+        };
+
+        let written = match (written, serde_json::to_vec(&job)) {
+            (Ok(_), Ok(json)) => write_file(&path, json).await,
+            (Err(err), _) => Err(err),
+            (_, Err(err)) => Err(err.into()),
+        };
+
+        if let Err(err) = written {
+            error!("failed writing job {}: {}", job.response.id, err);
+            return;
+        }
+
+        let (dir, ttl, lock) = (self.path.clone(), self.ttl, self.prune.clone());
+
+        let _ = tokio::task::spawn_blocking(move || {
+            let _guard = lock.lock().unwrap();
+            prune(&dir, ttl);
+        })
+        .await;
+    }
+
+    async fn get(&self, id: &str) -> Option<Job> {
+        let path = self.job_path(id, "json")?;
+        let result_path = self.job_path(id, "result")?;
+        let modified = tokio::fs::metadata(&path).await.ok()?.modified().ok()?;
+
+        if modified.elapsed().unwrap_or_default() >= self.ttl {
+            let _ = tokio::fs::remove_file(&path).await;
+            let _ = tokio::fs::remove_file(&result_path).await;
+            return None;
+        }
+
+        let data = tokio::fs::read(&path).await.ok()?;
+        let mut job: Job = serde_json::from_slice(&data).ok()?;
+
+        if let Ok(result) = tokio::fs::read(&result_path).await {
+            job.response.result = Some(RenderingResponse::decode(result.as_slice()).ok()?);
+        }
+
+        Some(job)
+    }
+}
+
+// Written to a temporary file first, so readers never see partial files
+async fn write_file(path: &Path, data: Vec<u8>) -> std::io::Result<()> {
+    let temporary = path.with_extension(format!("{}.tmp", ulid::Ulid::new()));
+    let written = match tokio::fs::write(&temporary, data).await {
+        Ok(_) => tokio::fs::rename(&temporary, path).await,
+        Err(err) => Err(err),
+    };
+
+    if written.is_err() {
+        let _ = tokio::fs::remove_file(&temporary).await;
+    }
+
+    written
+}
+
+// Removes jobs and results which haven't been updated within the TTL
+fn prune(dir: &Path, ttl: Duration) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let expired = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().unwrap_or_default() >= ttl);
+
+        if expired {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+pub struct Jobs {
+    pub store: Arc<dyn JobStore>,
+    handles: Mutex<HashMap<String, AbortHandle>>,
+    // Serializes state transitions, so a job which ended is never moved to another state
+    transitions: tokio::sync::Mutex<()>,
+}
+
+impl Jobs {
+    pub fn new(store: Arc<dyn JobStore>) -> Self {
+        Jobs {
+            store,
+            handles: Mutex::new(HashMap::new()),
+            transitions: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub async fn submit(&self, id: String, owner: Option<String>) {
+        self.store.put(job(id, owner, State::Queued, None)).await;
+    }
+
+    // The handle is registered before the job can finish and remove it again
+    pub fn spawn<F>(&self, id: String, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let mut handles = self.handles.lock().unwrap();
+        let handle = tokio::spawn(future);
+        handles.insert(id, handle.abort_handle());
+        handle
+    }
+
+    pub async fn start(&self, id: String) {
+        self.transition(id, State::Running, None).await;
+    }
+
+    pub async fn finish(&self, id: String, state: State, result: Option<RenderingResponse>) {
+        self.handles.lock().unwrap().remove(&id);
+        self.transition(id, state, result).await;
+    }
+
+    pub async fn cancel(&self, id: &str) -> Option<Job> {
+        let _guard = self.transitions.lock().await;
+        let current = self.store.get(id).await?;
+
+        if is_final(current.response.state()) {
+            return Some(current);
+        }
+
+        if let Some(handle) = self.handles.lock().unwrap().remove(id) {
+            handle.abort();
+        }
+
+        let cancelled = job(id.to_string(), current.owner, State::Cancelled, None);
+        self.store.put(cancelled.clone()).await;
+        Some(cancelled)
+    }
+
+    async fn transition(&self, id: String, state: State, result: Option<RenderingResponse>) {
+        let _guard = self.transitions.lock().await;
+
+        // Jobs which expired or were evicted are not stored again without their owner
+        let owner = match self.store.get(&id).await {
+            Some(current) if is_final(current.response.state()) => return,
+            Some(current) => current.owner,
+            None => return,
+        };
+
+        self.store.put(job(id, owner, state, result)).await;
+    }
+}
+
+fn is_final(state: State) -> bool {
+    !matches!(state, State::Queued | State::Running)
+}
+
+fn job(id: String, owner: Option<String>, state: State, result: Option<RenderingResponse>) -> Job {
+    Job {
+        owner,
+        response: JobResponse {
+            id,
+            state: state as i32,
+            result,
+        },
+    }
+}
@@ -1,7 +1,8 @@
 use config::{Case, Config, File};
 use env_logger::WriteStyle;
-use log::info;
+use log::{debug, info};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::{env, error::Error, net::ToSocketAddrs};
 use tokio::sync::mpsc;
 
@@ -9,16 +10,37 @@ use tonic::codegen::InterceptedService;
 use tonic::{transport::Server, Request, Status};
 use tonic_health::ServingStatus;
 
+use crate::auth::Auth;
+use crate::events::create_event_sink;
+use crate::identity::Identity;
+use crate::jobs::{create_job_store, Jobs};
 use crate::proto::pdf_rendering::pdf_rendering_service_server::PdfRenderingServiceServer;
 use crate::renderer::start_renderer;
+use crate::request_log::{json_format, redact_metadata};
 use crate::server::PDFServer;
+use crate::storage::create_storage;
 use crate::types::{IDExtension, InternalRequest};
 
+mod auth;
+mod browser;
+mod cache;
+mod egress;
+mod events;
+mod fetch;
+mod icc;
+mod identity;
+mod jobs;
 mod pdf_utils;
+mod pdfa;
+mod policy;
 mod proto;
+mod proxy;
 mod renderer;
+mod request_log;
 mod s3;
+mod scheduler;
 mod server;
+mod storage;
 mod types;
 
 #[tokio::main]
@@ -32,10 +54,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .as_str(),
     );
 
-    env_logger::builder()
-        .filter_level(level.expect("invalid level"))
-        .write_style(WriteStyle::Always)
-        .init();
+    let mut logger = env_logger::builder();
+    logger.filter_level(level.expect("invalid level"));
+
+    match config
+        .get_string("logger.console.format")
+        .unwrap_or("text".to_string())
+        .as_str()
+    {
+        "text" => logger.write_style(WriteStyle::Always),
+        "json" => logger.write_style(WriteStyle::Never).format(json_format),
+        other => panic!("unknown log format: {}", other),
+    };
+
+    logger.init();
 
     let metrics = tokio::runtime::Handle::current().metrics();
     info!("worker count: {}", metrics.num_workers());
@@ -52,17 +84,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .await;
 
     health_reporter
-        .set_service_status("readiness", ServingStatus::Serving)
+        .set_service_status("readiness", ServingStatus::NotServing)
         .await;
 
     let (tx, rx) = mpsc::channel::<InternalRequest>(32);
 
+    let identity = Arc::new(Identity::new(&config));
+
     let pdf_server = PDFServer {
         config: config.clone(),
         renderer: tx,
+        storage: create_storage(config.clone()),
+        events: create_event_sink(&config),
+        jobs: Arc::new(Jobs::new(create_job_store(&config))),
+        auth: Arc::new(Auth::new(config.clone(), identity)),
     };
 
-    start_renderer(rx).await?;
+    start_renderer(config.clone(), health_reporter.clone(), rx).await?;
 
     let pdf_service =
         InterceptedService::new(
@@ -102,12 +140,19 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+// Interceptors must return a Status, however large it is
+#[allow(clippy::result_large_err)]
 fn logging(mut req: Request<()>) -> Result<Request<()>, Status> {
     let id = ulid::Ulid::new();
 
     req.extensions_mut().insert(IDExtension { id });
 
-    info!("[{}] Received request: {:?}", id, req);
+    info!("[{}] Received request", id);
+    debug!(
+        "[{}] Request metadata: {:?}",
+        id,
+        redact_metadata(req.metadata())
+    );
     Ok(req)
 }
 
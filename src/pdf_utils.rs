@@ -1,12 +1,14 @@
-use log::error;
 use std::collections::BTreeMap;
 use std::io::{Cursor, Write};
 
-use crate::proto::pdf_rendering::MetaData;
+use crate::proto::pdf_rendering::attachment::AfRelationship;
+use crate::proto::pdf_rendering::{Attachment, MetaData};
+use chrono::Utc;
 use lopdf::Dictionary as LoDictionary;
 use lopdf::Object::*;
+use lopdf::Stream as LoStream;
 use lopdf::StringFormat::Literal;
-use lopdf::{Bookmark, Document, Object, ObjectId};
+use lopdf::{dictionary, text_string, Bookmark, Document, Object, ObjectId};
 
 pub fn merge_pdfs(documents: Vec<Document>) -> std::io::Result<Vec<u8>> {
     // Define a starting max_id (will be used as start index for object_ids)
@@ -26,7 +28,9 @@ pub fn merge_pdfs(documents: Vec<Document>) -> std::io::Result<Vec<u8>> {
         documents_pages.extend(
             doc.get_pages()
                 .into_values()
-                .map(|object_id| {
+                .filter_map(|object_id| {
+                    let object = doc.get_object(object_id).ok()?.to_owned();
+
                     if !first {
                         let bookmark = Bookmark::new(
                             format!("Page_{}", pagenum),
@@ -39,7 +43,7 @@ pub fn merge_pdfs(documents: Vec<Document>) -> std::io::Result<Vec<u8>> {
                         pagenum += 1;
                     }
 
-                    (object_id, doc.get_object(object_id).unwrap().to_owned())
+                    Some((object_id, object))
                 })
                 .collect::<BTreeMap<ObjectId, Object>>(),
         );
@@ -97,31 +101,24 @@ pub fn merge_pdfs(documents: Vec<Document>) -> std::io::Result<Vec<u8>> {
     }
 
     // If no "Pages" object found abort
-    if pages_object.is_none() {
-        error!("Pages root not found.");
-
-        return Ok(vec![]);
-    }
+    let Some(pages_object) = pages_object else {
+        return Err(std::io::Error::other("Pages root not found"));
+    };
 
     // Iterate over all "Page" objects and collect into the parent "Pages" created before
     for (object_id, object) in documents_pages.iter() {
         if let Ok(dictionary) = object.as_dict() {
             let mut dictionary = dictionary.clone();
-            dictionary.set("Parent", pages_object.as_ref().unwrap().0);
+            dictionary.set("Parent", pages_object.0);
 
             document.objects.insert(*object_id, Dictionary(dictionary));
         }
     }
 
     // If no "Catalog" found abort
-    if catalog_object.is_none() {
-        error!("Catalog root not found.");
-
-        return Ok(vec![]);
-    }
-
-    let catalog_object = catalog_object.unwrap();
-    let pages_object = pages_object.unwrap();
+    let Some(catalog_object) = catalog_object else {
+        return Err(std::io::Error::other("Catalog root not found"));
+    };
 
     // Build a new "Pages" with updated fields
     if let Ok(dictionary) = pages_object.1.as_dict() {
@@ -178,33 +175,35 @@ pub fn merge_pdfs(documents: Vec<Document>) -> std::io::Result<Vec<u8>> {
     let out_buf = Vec::new();
     let mut memory_cursor = Cursor::new(out_buf.clone());
 
-    document
-        .save_to(&mut memory_cursor)
-        .expect("failed saving pdf");
+    document.save_to(&mut memory_cursor)?;
 
-    memory_cursor.flush().expect("failed flushing");
+    memory_cursor.flush()?;
 
     Ok(memory_cursor.get_ref().to_vec())
 }
 
+pub fn page_count(file: &[u8]) -> Option<usize> {
+    Document::load_mem(file).ok().map(|d| d.get_pages().len())
+}
+
 pub fn add_pdf_metadata(file: Vec<u8>, meta: Option<MetaData>) -> std::io::Result<Vec<u8>> {
     match meta {
         None => Ok(file),
         Some(m) => {
-            let mut document = Document::load_mem(&file).expect("failed parsing pdf");
+            let mut document = Document::load_mem(&file).map_err(std::io::Error::other)?;
 
             let mut meta_list = Vec::new();
 
-            if m.title.is_some() {
-                meta_list.push(("Title", String(m.title.unwrap().into(), Literal)));
+            if let Some(title) = m.title {
+                meta_list.push(("Title", String(title.into(), Literal)));
             }
 
-            if m.creator.is_some() {
-                meta_list.push(("Creator", String(m.creator.unwrap().into(), Literal)));
+            if let Some(creator) = m.creator {
+                meta_list.push(("Creator", String(creator.into(), Literal)));
             }
 
-            if m.producer.is_some() {
-                meta_list.push(("Producer", String(m.producer.unwrap().into(), Literal)));
+            if let Some(producer) = m.producer {
+                meta_list.push(("Producer", String(producer.into(), Literal)));
             }
 
             document.set_object((1, 0), LoDictionary::from_iter(meta_list.clone()));
@@ -214,13 +213,109 @@ pub fn add_pdf_metadata(file: Vec<u8>, meta: Option<MetaData>) -> std::io::Resul
             let out_buf = Vec::new();
             let mut memory_cursor = Cursor::new(out_buf.clone());
 
-            document
-                .save_to(&mut memory_cursor)
-                .expect("failed saving PDF");
+            document.save_to(&mut memory_cursor)?;
 
-            memory_cursor.flush().expect("failed flushing");
+            memory_cursor.flush()?;
 
             Ok(memory_cursor.get_ref().to_vec())
         }
     }
 }
+
+pub fn add_attachments(file: Vec<u8>, attachments: Vec<Attachment>) -> std::io::Result<Vec<u8>> {
+    if attachments.is_empty() {
+        return Ok(file);
+    }
+
+    let mut document = Document::load_mem(&file).map_err(std::io::Error::other)?;
+
+    let mut names = Vec::new();
+    let mut associated_files = Vec::new();
+
+    for attachment in attachments {
+        let relationship = match attachment.relationship() {
+            AfRelationship::Source => "Source",
+            AfRelationship::Data => "Data",
+            AfRelationship::Alternative => "Alternative",
+            AfRelationship::Supplement => "Supplement",
+            AfRelationship::Unspecified => "Unspecified",
+        };
+
+        let file_id = document.add_object(LoStream::new(
+            dictionary! {
+                "Type" => "EmbeddedFile",
+                "Subtype" => attachment.content_type.as_str(),
+                "Params" => dictionary! {
+                    "Size" => attachment.data.len() as i64,
+                    "ModDate" => Object::from(Utc::now()),
+                },
+            },
+            attachment.data,
+        ));
+
+        let mut file_spec = dictionary! {
+            "Type" => "Filespec",
+            "F" => String(attachment.filename.clone().into_bytes(), Literal),
+            "UF" => text_string(&attachment.filename),
+            "EF" => dictionary! {
+                "F" => file_id,
+                "UF" => file_id,
+            },
+            "AFRelationship" => relationship,
+        };
+
+        if let Some(description) = attachment.description {
+            file_spec.set("Desc", text_string(&description));
+        }
+
+        let file_spec_id = document.add_object(file_spec);
+
+        names.push((attachment.filename, file_spec_id));
+        associated_files.push(Reference(file_spec_id));
+    }
+
+    // Keys of name trees have to be sorted
+    names.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let embedded_files = dictionary! {
+        "Names" => names
+            .into_iter()
+            .flat_map(|(name, id)| vec![text_string(&name), Reference(id)])
+            .collect::<Vec<_>>(),
+    };
+
+    let names_id = match document.catalog().and_then(|c| c.get(b"Names")) {
+        Ok(Reference(id)) => Some(*id),
+        _ => None,
+    };
+
+    let names = match names_id {
+        Some(id) => document.get_dictionary_mut(id),
+        None => {
+            let catalog = document.catalog_mut().map_err(std::io::Error::other)?;
+            if catalog.get(b"Names").and_then(Object::as_dict).is_err() {
+                catalog.set("Names", LoDictionary::new());
+            }
+            catalog.get_mut(b"Names").and_then(Object::as_dict_mut)
+        }
+    }
+    .map_err(std::io::Error::other)?;
+
+    names.set("EmbeddedFiles", embedded_files);
+
+    let catalog = document.catalog_mut().map_err(std::io::Error::other)?;
+    catalog.set("AF", associated_files);
+
+    document.compress();
+
+    let out_buf = Vec::new();
+    let mut memory_cursor = Cursor::new(out_buf.clone());
+
+    document
+        .save_to(&mut memory_cursor)
+        .map_err(std::io::Error::other)?;
+
+    memory_cursor.flush()?;
+
+    Ok(memory_cursor.get_ref().to_vec())
+}
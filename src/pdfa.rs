@@ -0,0 +1,430 @@
+use crate::icc::srgb_icc_profile;
+use crate::proto::pdf_rendering::output_options::PdfaConformance;
+use crate::proto::pdf_rendering::FacturX;
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, Utc};
+use lopdf::{
+    decode_text_string, dictionary, Dictionary, Document, Object, ObjectId, Stream, StringFormat,
+};
+use std::io::{Cursor, Write};
+
+pub fn convert_to_pdfa(
+    file: Vec<u8>,
+    conformance: PdfaConformance,
+    factur_x: Option<&FacturX>,
+) -> Result<Vec<u8>> {
+    let (part, version) = match conformance {
+        PdfaConformance::Pdfa1b => (1, "1.4"),
+        PdfaConformance::Pdfa2b => (2, "1.7"),
+        PdfaConformance::Pdfa3b => (3, "1.7"),
+    };
+
+    let mut document = Document::load_mem(&file)?;
+
+    // Documents requiring a password can't be decrypted
+    if document.is_encrypted() {
+        return Err(anyhow!("encrypted documents can't be converted to PDF/A"));
+    }
+    document.trailer.remove(b"Encrypt");
+
+    check_fonts_embedded(&document)?;
+
+    // The invoice XML is an embedded file
+    if factur_x.is_some() && conformance != PdfaConformance::Pdfa3b {
+        return Err(anyhow!("factur-x invoices require PDF/A-3"));
+    }
+
+    // Only PDF/A-3 allows embedding arbitrary files
+    if conformance != PdfaConformance::Pdfa3b && has_embedded_files(&document) {
+        return Err(anyhow!("embedded files require PDF/A-3"));
+    }
+
+    if conformance == PdfaConformance::Pdfa1b {
+        remove_transparency(&mut document)?;
+    }
+
+    remove_actions(&mut document);
+
+    let now = Utc::now();
+    let info_id = match document.trailer.get(b"Info").and_then(Object::as_reference) {
+        Ok(id) => id,
+        Err(_) => {
+            let id = document.add_object(Dictionary::new());
+            document.trailer.set("Info", id);
+            id
+        }
+    };
+
+    let info = document.get_dictionary_mut(info_id)?;
+    info.set("CreationDate", Object::from(now));
+    info.set("ModDate", Object::from(now));
+    let info = info.clone();
+
+    let text = |key: &[u8]| info.get(key).ok().and_then(|o| decode_text_string(o).ok());
+
+    let xmp = create_xmp(
+        part,
+        text(b"Title"),
+        text(b"Creator"),
+        text(b"Producer"),
+        now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        factur_x,
+    );
+
+    let metadata_id = document.add_object(
+        Stream::new(
+            dictionary! {
+                "Type" => "Metadata",
+                "Subtype" => "XML",
+            },
+            xmp.into_bytes(),
+        )
+        .with_compression(false),
+    );
+
+    let profile_id = document.add_object(Stream::new(
+        dictionary! {
+            "N" => 3,
+        },
+        srgb_icc_profile(),
+    ));
+
+    let output_intent_id = document.add_object(dictionary! {
+        "Type" => "OutputIntent",
+        "S" => "GTS_PDFA1",
+        "OutputConditionIdentifier" => Object::string_literal("sRGB IEC61966-2.1"),
+        "Info" => Object::string_literal("sRGB IEC61966-2.1"),
+        "DestOutputProfile" => profile_id,
+    });
+
+    let catalog = document.catalog_mut()?;
+    catalog.set("Metadata", metadata_id);
+    catalog.set("OutputIntents", vec![Object::Reference(output_intent_id)]);
+
+    let id = ulid::Ulid::new().0.to_be_bytes().to_vec();
+    document.trailer.set(
+        "ID",
+        vec![
+            Object::String(id.clone(), StringFormat::Hexadecimal),
+            Object::String(id, StringFormat::Hexadecimal),
+        ],
+    );
+
+    document.version = version.to_string();
+    document.compress();
+
+    let out_buf = Vec::new();
+    let mut memory_cursor = Cursor::new(out_buf.clone());
+
+    document.save_to(&mut memory_cursor)?;
+
+    memory_cursor.flush()?;
+
+    Ok(memory_cursor.get_ref().to_vec())
+}
+
+fn check_fonts_embedded(document: &Document) -> Result<()> {
+    for object in document.objects.values() {
+        let Ok(font) = object.as_dict() else {
+            continue;
+        };
+
+        if !font.has_type(b"Font") {
+            continue;
+        }
+
+        // Type0 fonts are checked through their descendants, Type3 fonts are drawn by glyph procedures
+        let subtype = font
+            .get(b"Subtype")
+            .and_then(Object::as_name)
+            .unwrap_or("".as_bytes());
+        if subtype == b"Type0" || subtype == b"Type3" {
+            continue;
+        }
+
+        let embedded = font
+            .get_deref(b"FontDescriptor", document)
+            .and_then(Object::as_dict)
+            .map(|descriptor| {
+                descriptor.has(b"FontFile")
+                    || descriptor.has(b"FontFile2")
+                    || descriptor.has(b"FontFile3")
+            })
+            .unwrap_or(false);
+
+        if !embedded {
+            let name = font
+                .get(b"BaseFont")
+                .and_then(Object::as_name)
+                .map(|n| String::from_utf8_lossy(n).to_string())
+                .unwrap_or("unknown".to_string());
+
+            return Err(anyhow!("font {} is not embedded", name));
+        }
+    }
+
+    Ok(())
+}
+
+fn has_embedded_files(document: &Document) -> bool {
+    document.objects.values().any(|object| match object {
+        Object::Stream(stream) => stream.dict.has_type(b"EmbeddedFile"),
+        _ => false,
+    })
+}
+
+fn remove_transparency(document: &mut Document) -> Result<()> {
+    for object in document.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(ref mut dict) => dict,
+            Object::Stream(ref mut stream) => &mut stream.dict,
+            _ => continue,
+        };
+
+        // Soft masks and constant alpha change the rendering and can't be removed
+        let soft_mask = dict
+            .get(b"SMask")
+            .map(|mask| mask.as_name().map(|n| n != b"None").unwrap_or(true))
+            .unwrap_or(false);
+        let alpha = [b"CA".as_slice(), b"ca".as_slice()].iter().any(|key| {
+            dict.get(key)
+                .and_then(Object::as_float)
+                .map(|v| v < 1.0)
+                .unwrap_or(false)
+        });
+
+        if soft_mask || alpha {
+            return Err(anyhow!("document uses transparency not allowed in PDF/A-1"));
+        }
+
+        let transparency_group = dict
+            .get(b"Group")
+            .and_then(Object::as_dict)
+            .and_then(|group| group.get(b"S"))
+            .and_then(Object::as_name)
+            .map(|s| s == b"Transparency")
+            .unwrap_or(false);
+
+        if transparency_group {
+            dict.remove(b"Group");
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_actions(document: &mut Document) {
+    let forbidden: Vec<ObjectId> = document
+        .objects
+        .iter()
+        .filter(|(_, object)| object.as_dict().map(is_forbidden_action).unwrap_or(false))
+        .map(|(id, _)| *id)
+        .collect();
+
+    for object in document.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(ref mut dict) => dict,
+            Object::Stream(ref mut stream) => &mut stream.dict,
+            _ => continue,
+        };
+
+        // Additional actions are not allowed at all
+        dict.remove(b"AA");
+
+        for key in [
+            b"A".as_slice(),
+            b"OpenAction".as_slice(),
+            b"Next".as_slice(),
+        ] {
+            let remove = match dict.get(key) {
+                Ok(Object::Reference(id)) => forbidden.contains(id),
+                Ok(Object::Dictionary(action)) => is_forbidden_action(action),
+                _ => false,
+            };
+
+            if remove {
+                dict.remove(key);
+            }
+        }
+
+        if let Ok(Object::Dictionary(names)) = dict.get_mut(b"Names") {
+            names.remove(b"JavaScript");
+        }
+    }
+
+    for id in forbidden {
+        document.objects.remove(&id);
+    }
+
+    // Name dictionaries may be referenced from the catalog
+    if let Ok(Object::Reference(names_id)) =
+        document.catalog().and_then(|c| c.get(b"Names")).cloned()
+    {
+        if let Ok(names) = document.get_dictionary_mut(names_id) {
+            names.remove(b"JavaScript");
+        }
+    }
+}
+
+fn is_forbidden_action(dict: &Dictionary) -> bool {
+    matches!(
+        dict.get(b"S").and_then(Object::as_name),
+        Ok(b"JavaScript")
+            | Ok(b"Launch")
+            | Ok(b"Sound")
+            | Ok(b"Movie")
+            | Ok(b"ResetForm")
+            | Ok(b"ImportData")
+    )
+}
+
+fn create_xmp(
+    part: u8,
+    title: Option<String>,
+    creator: Option<String>,
+    producer: Option<String>,
+    date: String,
+    factur_x: Option<&FacturX>,
+) -> String {
+    let mut properties = vec![
+        format!("<pdfaid:part>{}</pdfaid:part>", part),
+        "<pdfaid:conformance>B</pdfaid:conformance>".to_string(),
+        "<dc:format>application/pdf</dc:format>".to_string(),
+        format!("<xmp:CreateDate>{}</xmp:CreateDate>", date),
+        format!("<xmp:ModifyDate>{}</xmp:ModifyDate>", date),
+        format!("<xmp:MetadataDate>{}</xmp:MetadataDate>", date),
+    ];
+
+    if let Some(title) = title {
+        properties.push(format!(
+            "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>",
+            escape_xml(&title)
+        ));
+    }
+
+    if let Some(creator) = creator {
+        properties.push(format!(
+            "<xmp:CreatorTool>{}</xmp:CreatorTool>",
+            escape_xml(&creator)
+        ));
+    }
+
+    if let Some(producer) = producer {
+        properties.push(format!(
+            "<pdf:Producer>{}</pdf:Producer>",
+            escape_xml(&producer)
+        ));
+    }
+
+    format!(
+        r#"<?xpacket begin="{}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+<rdf:Description rdf:about=""
+ xmlns:pdfaid="http://www.aiim.org/pdfa/ns/id/"
+ xmlns:dc="http://purl.org/dc/elements/1.1/"
+ xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+ xmlns:pdf="http://ns.adobe.com/pdf/1.3/">
+{}
+</rdf:Description>{}
+</rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#,
+        '\u{feff}',
+        properties.join("\n"),
+        factur_x.map(factur_x_xmp).unwrap_or_default()
+    )
+}
+
+// The fx properties are not part of PDF/A, so their schema has to be declared
+// by a PDF/A extension schema
+fn factur_x_xmp(factur_x: &FacturX) -> String {
+    let namespace = factur_x
+        .namespace
+        .as_deref()
+        .unwrap_or("urn:factur-x:pdfa:CrossIndustryDocument:invoice:1p0#");
+
+    let values = [
+        (
+            "DocumentFileName",
+            factur_x
+                .document_file_name
+                .as_deref()
+                .unwrap_or("factur-x.xml"),
+            "name of the embedded XML invoice file",
+        ),
+        (
+            "DocumentType",
+            factur_x.document_type.as_deref().unwrap_or("INVOICE"),
+            "INVOICE",
+        ),
+        (
+            "Version",
+            factur_x.version.as_deref().unwrap_or("1.0"),
+            "The actual version of the Factur-X XML schema",
+        ),
+        (
+            "ConformanceLevel",
+            factur_x.conformance_level.as_str(),
+            "The conformance level of the embedded Factur-X data",
+        ),
+    ];
+
+    let properties: Vec<String> = values
+        .iter()
+        .map(|(name, value, _)| format!("<fx:{0}>{1}</fx:{0}>", name, escape_xml(value)))
+        .collect();
+
+    let schema: Vec<String> = values
+        .iter()
+        .map(|(name, _, description)| {
+            format!(
+                r#"<rdf:li rdf:parseType="Resource">
+<pdfaProperty:name>{}</pdfaProperty:name>
+<pdfaProperty:valueType>Text</pdfaProperty:valueType>
+<pdfaProperty:category>external</pdfaProperty:category>
+<pdfaProperty:description>{}</pdfaProperty:description>
+</rdf:li>"#,
+                name, description
+            )
+        })
+        .collect();
+
+    format!(
+        r#"
+<rdf:Description rdf:about="" xmlns:fx="{0}">
+{1}
+</rdf:Description>
+<rdf:Description rdf:about=""
+ xmlns:pdfaExtension="http://www.aiim.org/pdfa/ns/extension/"
+ xmlns:pdfaSchema="http://www.aiim.org/pdfa/ns/schema#"
+ xmlns:pdfaProperty="http://www.aiim.org/pdfa/ns/property#">
+<pdfaExtension:schemas>
+<rdf:Bag>
+<rdf:li rdf:parseType="Resource">
+<pdfaSchema:schema>Factur-X PDFA Extension Schema</pdfaSchema:schema>
+<pdfaSchema:namespaceURI>{0}</pdfaSchema:namespaceURI>
+<pdfaSchema:prefix>fx</pdfaSchema:prefix>
+<pdfaSchema:property>
+<rdf:Seq>
+{2}
+</rdf:Seq>
+</pdfaSchema:property>
+</rdf:li>
+</rdf:Bag>
+</pdfaExtension:schemas>
+</rdf:Description>"#,
+        escape_xml(namespace),
+        properties.join("\n"),
+        schema.join("\n")
+    )
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,320 @@
+use config::Config;
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+// Decides which URLs documents, their sub-resources and templates may be loaded from
+#[derive(Clone)]
+pub struct UrlPolicy {
+    schemes: Vec<String>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    block_private: bool,
+}
+
+impl UrlPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        let list = |key: &str| -> Option<Vec<String>> {
+            config.get_array(key).ok().map(|values| {
+                values
+                    .into_iter()
+                    .filter_map(|v| v.into_string().ok())
+                    .map(|v| v.to_lowercase())
+                    .collect()
+            })
+        };
+
+        UrlPolicy {
+            schemes: list("security.urls.schemes")
+                .unwrap_or_else(|| vec!["http".to_string(), "https".to_string()]),
+            allow: list("security.urls.allow").unwrap_or_default(),
+            deny: list("security.urls.deny").unwrap_or_default(),
+            block_private: config
+                .get_bool("security.urls.block_private")
+                .unwrap_or(true),
+        }
+    }
+
+    // Resolves the host to check its addresses, this blocks and must not be called
+    // from async code directly
+    pub fn check(&self, url: &str) -> Result<(), String> {
+        let (host, port) = self.check_host(url)?;
+
+        if !self.block_private {
+            return Ok(());
+        }
+
+        // Hosts which do not resolve here cannot be reached directly,
+        // behind a proxy they are resolved by the proxy
+        let addresses = resolve(&host, port).unwrap_or_default();
+
+        check_addresses(&host, &addresses)
+    }
+
+    // Resolves the host of an allowed URL to the addresses to connect to. Connecting to
+    // these instead of the host keeps it from resolving to another address after the check.
+    pub fn resolve(&self, url: &str) -> Result<Vec<SocketAddr>, String> {
+        let (host, port) = self.check_host(url)?;
+
+        let addresses = resolve(&host, port)
+            .map_err(|err| format!("host {} does not resolve: {}", host, err))?;
+
+        if self.block_private {
+            check_addresses(&host, &addresses)?;
+        }
+
+        Ok(addresses)
+    }
+
+    // Checks the URL without resolving its host, only literal addresses are checked
+    pub fn check_unresolved(&self, url: &str) -> Result<(), String> {
+        let (host, port) = self.check_host(url)?;
+
+        match host.parse::<IpAddr>() {
+            Ok(ip) if self.block_private => check_addresses(&host, &[SocketAddr::new(ip, port)]),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn allows_scheme(&self, scheme: &str) -> bool {
+        self.schemes.iter().any(|s| s == scheme)
+    }
+
+    fn check_host(&self, url: &str) -> Result<(String, u16), String> {
+        let url = Url::parse(url).map_err(|err| format!("invalid url {}: {}", url, err))?;
+
+        if !self.allows_scheme(url.scheme()) {
+            return Err(format!("scheme {} is not allowed", url.scheme()));
+        }
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("url {} has no host", url))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .trim_end_matches('.')
+            .to_lowercase();
+
+        if self.deny.iter().any(|p| matches_host(p, &host)) {
+            return Err(format!("host {} is denied", host));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|p| matches_host(p, &host)) {
+            return Err(format!("host {} is not allowed", host));
+        }
+
+        Ok((host, url.port_or_known_default().unwrap_or(80)))
+    }
+}
+
+fn resolve(host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(vec![SocketAddr::new(ip, port)]),
+        Err(_) => (host, port)
+            .to_socket_addrs()
+            .map(|addresses| addresses.collect()),
+    }
+}
+
+fn check_addresses(host: &str, addresses: &[SocketAddr]) -> Result<(), String> {
+    match addresses.iter().find(|a| is_private(a.ip())) {
+        Some(address) => Err(format!(
+            "host {} resolves to private address {}",
+            host,
+            address.ip()
+        )),
+        None => Ok(()),
+    }
+}
+
+// Patterns are exact host names, `*.example.com` for any subdomain or `*` for all hosts
+pub fn matches_host(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some("") => true,
+        Some(suffix) if suffix.starts_with('.') => host.ends_with(suffix),
+        _ => pattern.trim_end_matches('.') == host,
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || a == 0
+                // Shared address space used for carrier-grade NAT
+                || (a == 100 && (b & 0xc0) == 64)
+                // Benchmarking networks and the reserved range
+                || (a == 198 && (b & 0xfe) == 18)
+                || a >= 240
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_private(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local and link-local addresses
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+// IPv6 addresses which reach an IPv4 host, the host is checked instead
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let [.., a, b, c, d] = ip.octets();
+
+    match ip.segments() {
+        // NAT64
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(Ipv4Addr::new(a, b, c, d)),
+        // 6to4
+        [0x2002, high, low, ..] => Some(Ipv4Addr::from(((high as u32) << 16) | low as u32)),
+        // IPv4-mapped and IPv4-compatible
+        _ => ip.to_ipv4(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> UrlPolicy {
+        UrlPolicy {
+            schemes: vec!["http".to_string(), "https".to_string()],
+            allow: allow.iter().map(|h| h.to_string()).collect(),
+            deny: deny.iter().map(|h| h.to_string()).collect(),
+            block_private: true,
+        }
+    }
+
+    #[test]
+    fn matches_exact_hosts() {
+        assert!(matches_host("example.com", "example.com"));
+        assert!(matches_host("example.com.", "example.com"));
+        assert!(!matches_host("example.com", "www.example.com"));
+        assert!(!matches_host("example.com", "example.com.evil.org"));
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches_host("*", "example.com"));
+        assert!(matches_host("*.example.com", "www.example.com"));
+        assert!(matches_host("*.example.com", "a.b.example.com"));
+        assert!(!matches_host("*.example.com", "example.com"));
+        assert!(!matches_host("*.example.com", "badexample.com"));
+        assert!(!matches_host("*example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn detects_private_addresses() {
+        for ip in [
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "::10.0.0.1",
+            "::127.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:a00:1::1",
+            "2002:7f00:1::",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{} is private", ip);
+        }
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for ip in [
+            "93.184.216.34",
+            "8.8.8.8",
+            "100.128.0.1",
+            "172.32.0.1",
+            "2606:2800:220:1::1",
+            "::ffff:8.8.8.8",
+            "198.20.0.1",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+        ] {
+            assert!(!is_private(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[test]
+    fn resolves_allowed_urls_to_their_addresses() {
+        assert_eq!(
+            policy(&[], &[]).resolve("http://93.184.216.34:8080/index.html"),
+            Ok(vec!["93.184.216.34:8080".parse().unwrap()])
+        );
+        assert_eq!(
+            policy(&[], &[]).resolve("https://[2606:2800:220:1::1]/"),
+            Ok(vec!["[2606:2800:220:1::1]:443".parse().unwrap()])
+        );
+    }
+
+    #[test]
+    fn rejects_private_addresses() {
+        assert!(policy(&[], &[]).resolve("http://127.0.0.1/").is_err());
+        assert!(policy(&[], &[]).resolve("http://[::1]:8080/").is_err());
+        assert!(policy(&[], &[]).check("http://169.254.169.254/").is_err());
+    }
+
+    #[test]
+    fn checks_literal_addresses_without_resolving() {
+        assert!(policy(&[], &[])
+            .check_unresolved("http://[64:ff9b::7f00:1]/")
+            .is_err());
+        assert!(policy(&[], &[])
+            .check_unresolved("http://10.0.0.1:8080/")
+            .is_err());
+        assert!(policy(&[], &[])
+            .check_unresolved("http://does-not-resolve.invalid/")
+            .is_ok());
+        assert!(policy(&[], &["*.invalid"])
+            .check_unresolved("http://does-not-resolve.invalid/")
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_schemes_which_are_not_allowed() {
+        assert!(policy(&[], &[]).check("file:///etc/passwd").is_err());
+        assert!(policy(&[], &[]).check("ws://93.184.216.34/").is_err());
+        assert!(!policy(&[], &[]).allows_scheme("wss"));
+    }
+
+    #[test]
+    fn applies_allow_and_deny_lists() {
+        assert!(policy(&[], &["*.internal"])
+            .check("http://db.internal/")
+            .is_err());
+        assert!(policy(&["example.com"], &[])
+            .check("http://other.org/")
+            .is_err());
+        assert!(policy(&["*"], &["93.184.216.34"])
+            .check("http://93.184.216.34/")
+            .is_err());
+        assert!(policy(&["93.184.216.34"], &[])
+            .check("http://93.184.216.34/")
+            .is_ok());
+    }
+}
@@ -25,6 +25,15 @@ pub mod resourcebase {
 pub mod filter {
     tonic::include_proto!("io.restorecommerce.filter");
 }
+pub mod rule {
+    tonic::include_proto!("io.restorecommerce.rule");
+}
+pub mod access_control {
+    tonic::include_proto!("io.restorecommerce.access_control");
+}
+pub mod ostorage {
+    tonic::include_proto!("io.restorecommerce.ostorage");
+}
 
 pub mod pdf_rendering {
     tonic::include_proto!("io.restorecommerce.pdf_rendering");
@@ -0,0 +1,123 @@
+use crate::policy::matches_host;
+use crate::proto::pdf_rendering::ProxyOptions;
+use crate::types::RenderError;
+use config::Config;
+use reqwest::{NoProxy, Url};
+
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Proxy {
+    pub server: String,
+    pub no_proxy: Vec<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Proxy {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let server = config.get_string("proxy.server").unwrap_or_default();
+        if server.is_empty() {
+            return None;
+        }
+
+        Some(Proxy {
+            server,
+            no_proxy: config
+                .get_array("proxy.no_proxy")
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|host| host.into_string().ok())
+                .collect(),
+            username: config
+                .get_string("proxy.username")
+                .ok()
+                .filter(|u| !u.is_empty()),
+            password: config
+                .get_string("proxy.password")
+                .ok()
+                .filter(|p| !p.is_empty()),
+        })
+    }
+
+    // A proxy given with the document replaces the configured one, an empty server
+    // connects directly. Other proxy servers must be allowed by `proxy.allow`, so that
+    // documents can't make the service connect to arbitrary hosts.
+    pub fn resolve(
+        config: &Config,
+        options: Option<&ProxyOptions>,
+    ) -> Result<Option<Self>, RenderError> {
+        let Some(options) = options else {
+            return Ok(Self::from_config(config));
+        };
+
+        let server = options.server.clone().unwrap_or_default();
+        if server.is_empty() {
+            return Ok(None);
+        }
+
+        let allowed: Vec<String> = config
+            .get_array("proxy.allow")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|host| host.into_string().ok())
+            .map(|host| host.to_lowercase())
+            .collect();
+
+        // Chrome accepts proxy servers without a scheme
+        let url = if server.contains("://") {
+            Url::parse(&server)
+        } else {
+            Url::parse(&format!("http://{}", server))
+        };
+        let host = url
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_lowercase()))
+            .ok_or_else(|| RenderError::InvalidInput(format!("invalid proxy server {}", server)))?;
+
+        if !allowed.iter().any(|pattern| matches_host(pattern, &host)) {
+            return Err(RenderError::Forbidden(format!(
+                "proxy server {} is not allowed",
+                host
+            )));
+        }
+
+        Ok(Some(Proxy {
+            server,
+            no_proxy: options.no_proxy.clone(),
+            username: options.username.clone().filter(|u| !u.is_empty()),
+            password: options.password.clone().filter(|p| !p.is_empty()),
+        }))
+    }
+
+    // Browsers only need to know where to connect, credentials are answered per tab
+    pub fn endpoint(&self) -> Self {
+        Proxy {
+            server: self.server.clone(),
+            no_proxy: self.no_proxy.clone(),
+            username: None,
+            password: None,
+        }
+    }
+
+    // Chrome does not accept credentials in the proxy URL, they are answered
+    // to the proxy authentication challenge of each tab instead
+    pub fn chrome_args(&self) -> Vec<String> {
+        let mut args = vec![format!("--proxy-server={}", self.server)];
+
+        if !self.no_proxy.is_empty() {
+            args.push(format!("--proxy-bypass-list={}", self.no_proxy.join(";")));
+        }
+
+        args
+    }
+
+    pub fn client(&self) -> reqwest::Result<reqwest::Proxy> {
+        let mut proxy = reqwest::Proxy::all(&self.server)?
+            .no_proxy(NoProxy::from_string(&self.no_proxy.join(",")));
+
+        if let Some(username) = &self.username {
+            proxy = proxy.basic_auth(username, self.password.as_deref().unwrap_or_default());
+        }
+
+        Ok(proxy)
+    }
+}
@@ -1,17 +1,47 @@
+use crate::browser::{BrowserPool, Instance};
+use crate::cache::{cache_key, create_cache, RenderCache};
+use crate::fetch::fetch_template;
+use crate::policy::UrlPolicy;
 use crate::proto::pdf_rendering::pdf_options::PaperFormat;
+use crate::proto::pdf_rendering::render_options::{FooterTemplate, HeaderTemplate};
 use crate::proto::pdf_rendering::render_source::Content;
-use crate::proto::pdf_rendering::{RenderData, RenderOptions};
-use crate::types::{InternalRequest, RendererResponse};
+use crate::proto::pdf_rendering::wait_condition::Condition;
+use crate::proto::pdf_rendering::{
+    CacheControl, RenderOptions, RenderSource, RenderTimeouts, WaitCondition,
+};
+use crate::proxy::Proxy;
+use crate::scheduler::{RenderTask, Scheduler};
+use crate::types::{Assets, InternalRequest, InternalResponse, RenderError, RendererResponse};
 use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use config::Config;
+use headless_chrome::browser::context::Context;
 use headless_chrome::browser::default_executable;
+use headless_chrome::browser::tab::RequestPausedDecision;
+use headless_chrome::browser::transport::{SessionId, Transport};
+use headless_chrome::protocol::cdp::types::Event;
+use headless_chrome::protocol::cdp::Emulation::SetScriptExecutionDisabled;
+use headless_chrome::protocol::cdp::Fetch::events::RequestPausedEvent;
+use headless_chrome::protocol::cdp::Fetch::{
+    AuthChallengeResponse, AuthChallengeResponseResponse, AuthChallengeSource, ContinueRequest,
+    ContinueWithAuth, FailRequest, HeaderEntry,
+};
+use headless_chrome::protocol::cdp::Network::{
+    self, CookieParam, ErrorReason, Headers, ResourceType, SetBlockedURLs,
+};
 use headless_chrome::types::PrintToPdfOptions;
-use headless_chrome::{Browser, LaunchOptionsBuilder, Tab};
+use headless_chrome::Tab;
+use log::warn;
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
 use std::error::Error;
-use std::io;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic_health::server::HealthReporter;
 
 impl PaperFormat {
     pub fn width(&self) -> f32 {
@@ -47,11 +77,146 @@ impl PaperFormat {
     }
 }
 
+pub async fn resolve_templates(
+    config: &Config,
+    options: Option<RenderOptions>,
+    proxy: Option<&Proxy>,
+) -> Result<(Option<String>, Option<String>)> {
+    let mut header = None;
+    let mut footer = None;
+
+    if let Some(opt) = options {
+        header = match opt.header_template {
+            None => None,
+            Some(HeaderTemplate::HeaderHtml(html)) => Some(html),
+            Some(HeaderTemplate::HeaderUrl(url)) => {
+                Some(fetch_template(config, &url, proxy).await?)
+            }
+        };
+
+        footer = match opt.footer_template {
+            None => None,
+            Some(FooterTemplate::FooterHtml(html)) => Some(html),
+            Some(FooterTemplate::FooterUrl(url)) => {
+                Some(fetch_template(config, &url, proxy).await?)
+            }
+        };
+    }
+
+    Ok((header, footer))
+}
+
+#[derive(Copy, Clone)]
+pub enum Phase {
+    Navigation,
+    Wait,
+    Print,
+}
+
+impl Phase {
+    fn name(&self) -> &'static str {
+        match self {
+            Phase::Navigation => "navigation",
+            Phase::Wait => "wait",
+            Phase::Print => "print",
+        }
+    }
+}
+
+// Tracks the phase a render is in, so that a watchdog can abort the render once
+// a phase exceeds its timeout
+pub struct PhaseTracker {
+    navigation: Duration,
+    wait: Duration,
+    print: Duration,
+    current: Mutex<Option<(Phase, Instant)>>,
+    aborted: AtomicBool,
+}
+
+impl PhaseTracker {
+    pub fn new(config: &Config, timeouts: Option<&RenderTimeouts>) -> Self {
+        let timeout = |key: &str, value: Option<i64>, default: i64| {
+            Duration::from_millis(
+                value
+                    .unwrap_or(config.get_int(key).unwrap_or(default))
+                    .max(0) as u64,
+            )
+        };
+
+        PhaseTracker {
+            navigation: timeout(
+                "renderer.timeouts.navigation",
+                timeouts.and_then(|t| t.navigation),
+                30000,
+            ),
+            wait: timeout(
+                "renderer.timeouts.wait",
+                timeouts.and_then(|t| t.wait),
+                60000,
+            ),
+            print: timeout(
+                "renderer.timeouts.print",
+                timeouts.and_then(|t| t.print),
+                30000,
+            ),
+            current: Mutex::new(None),
+            aborted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn timeout(&self, phase: Phase) -> Duration {
+        match phase {
+            Phase::Navigation => self.navigation,
+            Phase::Wait => self.wait,
+            Phase::Print => self.print,
+        }
+    }
+
+    pub fn enter(&self, phase: Phase) -> Result<(), RenderError> {
+        self.check()?;
+        *self.current.lock().unwrap() = Some((phase, Instant::now()));
+        Ok(())
+    }
+
+    pub fn check(&self) -> Result<(), RenderError> {
+        if self.aborted.load(Ordering::SeqCst) {
+            return Err(RenderError::Cancelled);
+        }
+
+        Ok(())
+    }
+
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+
+    pub fn timed_out(&self) -> Option<RenderError> {
+        let (phase, started) = (*self.current.lock().unwrap())?;
+        let timeout = self.timeout(phase);
+
+        (started.elapsed() >= timeout).then(|| RenderError::timeout(phase.name(), timeout))
+    }
+
+    pub async fn expired(&self) -> RenderError {
+        loop {
+            if let Some(timeout) = self.timed_out() {
+                return timeout;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
 pub fn content_to_pdf(
+    config: &Config,
     tab: Arc<Tab>,
-    content: Content,
+    source: RenderSource,
     options: Option<RenderOptions>,
-) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    (header, footer): (Option<String>, Option<String>),
+    assets: Arc<Assets>,
+    phases: &PhaseTracker,
+) -> Result<Vec<u8>, RenderError> {
     let mut landscape = None;
     let mut display_header_footer = None;
     let mut print_background = Some(true);
@@ -69,11 +234,11 @@ pub fn content_to_pdf(
     let mut footer_template = None;
     let mut prefer_css_page_size = Some(true);
 
-    match options {
+    match options.as_ref() {
         None => {}
-        Some(opt) => match opt.puppeteer_options {
+        Some(opt) => match &opt.puppeteer_options {
             None => {}
-            Some(puppeteer) => match puppeteer.pdf_options {
+            Some(puppeteer) => match puppeteer.pdf_options.clone() {
                 None => {}
                 Some(pdf) => {
                     paper_width = pdf.paper_width.or(paper_width);
@@ -82,10 +247,11 @@ pub fn content_to_pdf(
                     landscape = pdf.landscape.or(landscape);
                     display_header_footer = pdf.display_header_footer.or(display_header_footer);
                     print_background = pdf.print_background.or(print_background);
-                    format = pdf
-                        .format
-                        .map(|t| PaperFormat::try_from(t).unwrap())
-                        .or(format);
+                    if let Some(f) = pdf.format {
+                        format = Some(PaperFormat::try_from(f).map_err(|_| {
+                            RenderError::InvalidInput(format!("invalid paper format: {}", f))
+                        })?);
+                    }
                     scale = pdf.scale.or(scale);
                     paper_width = pdf.paper_width.or(paper_width);
                     paper_height = pdf.paper_height.or(paper_height);
@@ -105,8 +271,21 @@ pub fn content_to_pdf(
         },
     }
 
-    paper_width = paper_width.or(Some(format.unwrap().width()));
-    paper_height = paper_height.or(Some(format.unwrap().height()));
+    // Templates from the top level render options take precedence
+    if header.is_some() || footer.is_some() {
+        header_template = header.or(header_template);
+        footer_template = footer.or(footer_template);
+        display_header_footer = display_header_footer.or(Some(true));
+    }
+
+    // Chrome prints its default header or footer for a missing template
+    if display_header_footer == Some(true) {
+        header_template = header_template.or_else(|| Some("<span></span>".to_string()));
+        footer_template = footer_template.or_else(|| Some("<span></span>".to_string()));
+    }
+
+    paper_width = paper_width.or(format.map(|f| f.width()));
+    paper_height = paper_height.or(format.map(|f| f.height()));
 
     let pdf_options = PrintToPdfOptions {
         paper_width: paper_width.map(|t| t as f64),
@@ -127,114 +306,722 @@ pub fn content_to_pdf(
         ..Default::default()
     };
 
-    let pdf = match content {
-        Content::Url(url) => tab
-            .navigate_to(url.as_str())?
-            .wait_until_navigated()?
-            .print_to_pdf(Some(pdf_options))?,
+    let network_idle = Arc::new(AtomicBool::new(false));
+    let wait_conditions = options
+        .as_ref()
+        .map(|o| o.wait_conditions.clone())
+        .unwrap_or_default();
+
+    if wait_conditions
+        .iter()
+        .any(|c| matches!(c.condition, Some(Condition::NetworkIdle(true))))
+    {
+        let idle = network_idle.clone();
+        tab.add_event_listener(Arc::new(move |event: &Event| {
+            if let Event::PageLifecycleEvent(lifecycle) = event {
+                match lifecycle.params.name.as_str() {
+                    "init" => idle.store(false, Ordering::SeqCst),
+                    "networkIdle" => idle.store(true, Ordering::SeqCst),
+                    _ => {}
+                }
+            }
+        }))?;
+    }
+
+    let mut _local_server = None;
+    let mut local_origin = None;
+    let mut source_headers = None;
+
+    let content = source
+        .content
+        .ok_or_else(|| RenderError::InvalidInput("missing source".to_string()))?;
+
+    let sandbox = options.as_ref().and_then(|o| o.sandbox).unwrap_or(false);
+
+    if sandbox
+        && wait_conditions
+            .iter()
+            .any(|c| matches!(c.condition, Some(Condition::Expression(_))))
+    {
+        return Err(RenderError::InvalidInput(
+            "expression wait conditions are not allowed in sandbox".to_string(),
+        ));
+    }
+
+    let url = match content {
+        Content::Url(_) if sandbox => {
+            return Err(RenderError::InvalidInput(
+                "sandbox requires an html source".to_string(),
+            ));
+        }
+        Content::Url(url) => {
+            let policy = UrlPolicy::from_config(config);
+            policy
+                .check(&url)
+                .map_err(|reason| RenderError::Forbidden(format!("url not allowed: {}", reason)))?;
+
+            let origin = Url::parse(&url)
+                .map_err(|err| RenderError::InvalidInput(format!("invalid url: {}", err)))?
+                .origin()
+                .ascii_serialization();
+
+            let mut headers: Vec<(String, String)> = source
+                .headers
+                .into_iter()
+                .map(|h| (h.name, h.value))
+                .collect();
+
+            if let Some(auth) = source.basic_auth {
+                let credentials = BASE64.encode(format!("{}:{}", auth.username, auth.password));
+                headers.push((
+                    "Authorization".to_string(),
+                    format!("Basic {}", credentials),
+                ));
+            }
+
+            if !headers.is_empty() {
+                source_headers = Some((origin, headers));
+            }
+
+            if !source.cookies.is_empty() {
+                let cookies = source
+                    .cookies
+                    .into_iter()
+                    .map(|cookie| CookieParam {
+                        name: cookie.name,
+                        value: cookie.value,
+                        url: cookie.domain.is_none().then(|| url.clone()),
+                        domain: cookie.domain,
+                        path: cookie.path,
+                        secure: None,
+                        http_only: None,
+                        same_site: None,
+                        expires: None,
+                        priority: None,
+                        same_party: None,
+                        source_scheme: None,
+                        source_port: None,
+                        partition_key: None,
+                    })
+                    .collect();
+
+                tab.set_cookies(cookies)
+                    .map_err(|err| RenderError::InvalidInput(format!("invalid cookie: {}", err)))?;
+            }
+
+            url
+        }
         Content::Html(data) => {
-            let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
-
-            let response = tiny_http::Response::new(
-                200.into(),
-                vec![
-                    tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/html"[..]).unwrap(),
-                ],
-                io::Cursor::new(data.clone()),
-                Some(data.clone().len()),
-                None,
-            );
+            let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").map_err(|err| {
+                RenderError::Internal(format!("failed starting local server: {}", err))
+            })?);
+            let port = server
+                .server_addr()
+                .to_ip()
+                .map(|addr| addr.port())
+                .ok_or_else(|| RenderError::Internal("local server has no port".to_string()))?;
 
+            // The document is served at the root, uploaded assets at their path
             let srv = server.clone();
             std::thread::spawn(move || {
-                let request = match srv.recv() {
-                    Ok(rq) => rq,
-                    Err(e) => {
-                        panic!("error: {}", e);
-                    }
-                };
+                for request in srv.incoming_requests() {
+                    // Asset paths are requested percent-encoded, e.g. images/my%20logo.png
+                    let path = request.url().split('?').next().unwrap_or_default();
+                    let path = percent_decode_str(path)
+                        .decode_utf8_lossy()
+                        .trim_start_matches('/')
+                        .to_string();
+
+                    let (content_type, body) = if path.is_empty() {
+                        ("text/html".to_string(), data.clone().into_bytes())
+                    } else {
+                        match assets.get(&path) {
+                            Some(asset) => (asset.content_type.clone(), asset.data.clone()),
+                            None => {
+                                let _ = request.respond(tiny_http::Response::empty(404));
+                                continue;
+                            }
+                        }
+                    };
 
-                let _ = request.respond(response);
+                    let mut response = tiny_http::Response::from_data(body);
+                    if let Ok(header) =
+                        tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+                    {
+                        response = response.with_header(header);
+                    }
 
-                drop(srv)
+                    let _ = request.respond(response);
+                }
             });
 
-            tab.navigate_to(
-                format!(
-                    "http://127.0.0.1:{}",
-                    server.server_addr().to_ip().unwrap().port()
-                )
-                .as_str(),
-            )?
-            .wait_until_navigated()?
-            .print_to_pdf(Some(pdf_options))?
+            let url = format!("http://127.0.0.1:{}", port);
+
+            _local_server = Some(LocalServer(server));
+            local_origin = Some(format!("{}/", url));
+
+            url
         }
     };
 
-    tab.close(true)?;
+    intercept_requests(config, &tab, local_origin, source_headers, sandbox)?;
+
+    if sandbox {
+        tab.call_method(SetScriptExecutionDisabled { value: true })
+            .map_err(|err| RenderError::Internal(format!("failed disabling scripts: {}", err)))?;
+    }
+
+    phases.enter(Phase::Navigation)?;
+    tab.set_default_timeout(phases.timeout(Phase::Navigation));
+    tab.navigate_to(url.as_str())
+        .and_then(|tab| tab.wait_until_navigated())
+        .map_err(|err| RenderError::Upstream(format!("navigation failed: {}", err)))?;
+
+    phases.enter(Phase::Wait)?;
+
+    // Slept in slices, so an aborted render doesn't keep its thread, and never longer than
+    // the wait phase may take
+    if let Some(wait) = options.as_ref().and_then(|o| o.wait_after_load_time) {
+        let wait = Duration::from_millis(wait.max(0) as u64).min(phases.timeout(Phase::Wait));
+        let deadline = Instant::now() + wait;
+
+        loop {
+            phases.check()?;
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+
+            std::thread::sleep(remaining.min(Duration::from_millis(100)));
+        }
+    }
+
+    for condition in wait_conditions {
+        wait_for_condition(config, &tab, &network_idle, phases, condition)?;
+    }
+
+    phases.enter(Phase::Print)?;
+    let pdf = tab.print_to_pdf(Some(pdf_options))?;
 
     Ok(pdf)
 }
 
-pub async fn start_renderer(mut rx: Receiver<InternalRequest>) -> Result<(), Box<dyn Error>> {
-    let options = LaunchOptionsBuilder::default()
-        .path(Some(default_executable().map_err(|e| anyhow!(e))?))
-        .sandbox(false)
-        .idle_browser_timeout(Duration::MAX)
-        .build()
-        .unwrap();
+// Fails every request of the page, including redirects and sub-resources, which is not
+// allowed by the URL policy. The local server of HTML documents is always reachable.
+// Sandboxed documents may only load from the local server and not navigate away.
+// Headers of a url source are only added to requests to its origin.
+fn intercept_requests(
+    config: &Config,
+    tab: &Arc<Tab>,
+    local_origin: Option<String>,
+    source_headers: Option<(String, Vec<(String, String)>)>,
+    sandbox: bool,
+) -> Result<(), RenderError> {
+    let policy = UrlPolicy::from_config(config);
 
-    tokio::spawn(async move {
-        let browser = Arc::new(Browser::new(options).expect("failed instantiating browser"));
+    // WebSockets are not paused by the fetch domain, so their schemes are blocked
+    // by the network domain unless the policy allows them
+    let blocked: Vec<String> = ["ws", "wss"]
+        .iter()
+        .filter(|scheme| sandbox || !policy.allows_scheme(scheme))
+        .map(|scheme| format!("{}://*", scheme))
+        .collect();
 
-        while let Some(cmd) = rx.recv().await {
-            handle_cmd(browser.clone(), cmd);
+    if !blocked.is_empty() {
+        tab.call_method(Network::Enable {
+            max_total_buffer_size: None,
+            max_resource_buffer_size: None,
+            max_post_data_size: None,
+            report_direct_socket_traffic: None,
+            enable_durable_messages: None,
+        })
+        .and_then(|_| tab.call_method(SetBlockedURLs { urls: blocked }))
+        .map_err(|err| RenderError::Internal(format!("failed blocking websockets: {}", err)))?;
+    }
+
+    tab.enable_request_interception(Arc::new(
+        move |_transport: Arc<Transport>, _session: SessionId, paused: RequestPausedEvent| {
+            let url = &paused.params.request.url;
+
+            let local = local_origin
+                .as_ref()
+                .is_some_and(|origin| url.starts_with(origin.as_str()));
+            let inline = ["data:", "blob:", "about:"]
+                .iter()
+                .any(|scheme| url.starts_with(scheme));
+
+            let allowed = if sandbox {
+                let navigation = paused.params.resource_Type == ResourceType::Document
+                    && local_origin.as_ref() != Some(url);
+
+                match (local || inline, navigation) {
+                    (_, true) => Err("navigation is not allowed in sandbox".to_string()),
+                    (true, false) => Ok(()),
+                    (false, false) => Err("network access is not allowed in sandbox".to_string()),
+                }
+            } else if local || inline {
+                Ok(())
+            } else {
+                // Resolving here would block the event thread of the tab, the addresses are
+                // checked by the egress proxy or resolved by the configured proxy instead
+                policy.check_unresolved(url)
+            };
+
+            match allowed {
+                Ok(_) => match &source_headers {
+                    Some((origin, headers))
+                        if Url::parse(url)
+                            .is_ok_and(|u| &u.origin().ascii_serialization() == origin) =>
+                    {
+                        RequestPausedDecision::Continue(Some(ContinueRequest {
+                            request_id: paused.params.request_id,
+                            url: None,
+                            method: None,
+                            post_data: None,
+                            headers: Some(with_headers(&paused.params.request.headers, headers)),
+                            intercept_response: None,
+                        }))
+                    }
+                    _ => RequestPausedDecision::Continue(None),
+                },
+                Err(reason) => {
+                    warn!("blocked request to {}: {}", url, reason);
+                    RequestPausedDecision::Fail(FailRequest {
+                        request_id: paused.params.request_id,
+                        error_reason: ErrorReason::BlockedByClient,
+                    })
+                }
+            }
+        },
+    ))
+    .and_then(|_| tab.enable_fetch(None, Some(true)).map(|_| ()))
+    .map_err(|err| RenderError::Internal(format!("failed intercepting requests: {}", err)))
+}
+
+// Provides the proxy credentials to challenges of the proxy only, so that servers can't
+// request them by answering with a 401. Listeners see events before the tab answers
+// them itself by deferring to the browser, which cancels challenges of servers.
+fn answer_proxy_auth(tab: &Arc<Tab>, proxy: &Proxy) -> Result<(), RenderError> {
+    let weak = Arc::downgrade(tab);
+    let (username, password) = (proxy.username.clone(), proxy.password.clone());
+
+    tab.add_event_listener(Arc::new(move |event: &Event| {
+        let Event::FetchAuthRequired(required) = event else {
+            return;
+        };
+
+        if required.params.auth_challenge.source != Some(AuthChallengeSource::Proxy) {
+            return;
         }
-    });
 
-    Ok(())
+        let Some(tab) = weak.upgrade() else {
+            return;
+        };
+
+        let answered = tab.call_method(ContinueWithAuth {
+            request_id: required.params.request_id.clone(),
+            auth_challenge_response: AuthChallengeResponse {
+                response: AuthChallengeResponseResponse::ProvideCredentials,
+                username: username.clone(),
+                password: password.clone(),
+            },
+        });
+
+        if let Err(err) = answered {
+            warn!("failed answering proxy authentication: {}", err);
+        }
+    }))
+    .map(|_| ())
+    .map_err(|err| RenderError::Internal(format!("failed enabling proxy auth: {}", err)))
+}
+
+// Continuing a request with headers replaces all of its headers, so they are merged
+fn with_headers(original: &Headers, extra: &[(String, String)]) -> Vec<HeaderEntry> {
+    let mut entries: Vec<HeaderEntry> = original
+        .0
+        .as_ref()
+        .and_then(|headers| headers.as_object())
+        .map(|headers| {
+            headers
+                .iter()
+                .filter(|(name, _)| !extra.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)))
+                .filter_map(|(name, value)| {
+                    Some(HeaderEntry {
+                        name: name.clone(),
+                        value: value.as_str()?.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    entries.extend(extra.iter().map(|(name, value)| HeaderEntry {
+        name: name.clone(),
+        value: value.clone(),
+    }));
+
+    entries
+}
+
+// Stops the local server once rendering finished or failed
+struct LocalServer(Arc<tiny_http::Server>);
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.0.unblock();
+    }
+}
+
+fn wait_for_condition(
+    config: &Config,
+    tab: &Arc<Tab>,
+    network_idle: &Arc<AtomicBool>,
+    phases: &PhaseTracker,
+    condition: WaitCondition,
+) -> Result<(), RenderError> {
+    // A condition never waits longer than the whole wait phase may take
+    let timeout = Duration::from_millis(
+        condition
+            .timeout
+            .unwrap_or(config.get_int("renderer.wait.timeout").unwrap_or(30000))
+            .max(0) as u64,
+    )
+    .min(phases.timeout(Phase::Wait));
+    let deadline = Instant::now() + timeout;
+
+    match condition.condition {
+        None => Ok(()),
+        Some(Condition::Selector(selector)) => tab
+            .wait_for_element_with_custom_timeout(selector.as_str(), timeout)
+            .map(|_| ())
+            .map_err(|_| {
+                RenderError::Timeout(format!(
+                    "timed out after {}ms waiting for selector '{}'",
+                    timeout.as_millis(),
+                    selector
+                ))
+            }),
+        Some(Condition::Expression(expression)) => loop {
+            phases.check()?;
+
+            let result = tab.evaluate(expression.as_str(), true)?;
+            if result.value == Some(serde_json::Value::Bool(true)) {
+                return Ok(());
+            }
+
+            if Instant::now() >= deadline {
+                return Err(RenderError::Timeout(format!(
+                    "timed out after {}ms waiting for expression '{}'",
+                    timeout.as_millis(),
+                    expression
+                )));
+            }
+
+            std::thread::sleep(Duration::from_millis(100));
+        },
+        Some(Condition::NetworkIdle(false)) => Ok(()),
+        Some(Condition::NetworkIdle(true)) => loop {
+            phases.check()?;
+
+            if network_idle.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            if Instant::now() >= deadline {
+                return Err(RenderError::Timeout(format!(
+                    "timed out after {}ms waiting for network idle",
+                    timeout.as_millis()
+                )));
+            }
+
+            std::thread::sleep(Duration::from_millis(100));
+        },
+    }
 }
 
-pub fn handle_cmd(browser: Arc<Browser>, cmd: InternalRequest) {
+pub async fn start_renderer(
+    config: Config,
+    health: HealthReporter,
+    mut rx: Receiver<InternalRequest>,
+) -> Result<(), Box<dyn Error>> {
+    // Fail early if there is no browser to supervise at all
+    default_executable().map_err(|e| anyhow!(e))?;
+
+    let max_tabs = at_least_one(&config, "renderer.max_tabs", 8);
+    let max_queued = at_least_one(&config, "renderer.max_queued", 256);
+    let health_interval = Duration::from_millis(
+        config
+            .get_int("renderer.browser.health_interval")
+            .unwrap_or(5000) as u64,
+    );
+
+    let scheduler = Arc::new(Scheduler::new(max_queued));
+    let cache = create_cache(&config);
+
+    let queue = scheduler.clone();
+    let lookup_cache = cache.clone();
     tokio::spawn(async move {
-        let (tx, mut rx) = mpsc::channel::<RendererResponse>(32);
+        while let Some(cmd) = rx.recv().await {
+            let mut tasks = Vec::with_capacity(cmd.data.len());
 
-        let data = cmd.data;
-        for (i, req) in data.iter().enumerate() {
-            let tab = browser.new_tab().expect("failed opening new browser tab");
-            handle_req(tab, req, tx.clone(), i);
-        }
+            // Cached documents are answered right away and never queued
+            for mut task in RenderTask::split(cmd) {
+                if let Some(cache) = &lookup_cache {
+                    if let Some(pdf) = lookup(cache.as_ref(), &mut task).await {
+                        reply(task, Ok(pdf), true);
+                        continue;
+                    }
+                }
 
-        let mut rendered = Vec::with_capacity(data.clone().len());
+                tasks.push(task);
+            }
 
-        for _ in data.clone().iter() {
-            rendered.push(rx.recv().await.unwrap());
+            if let Err(tasks) = queue.push(tasks) {
+                for task in tasks {
+                    reply(task, Err(RenderError::QueueFull), false);
+                }
+            }
         }
+    });
+
+    let browsers = Arc::new(BrowserPool::new(&config, health));
+    browsers.clone().monitor(health_interval);
+
+    tokio::spawn(async move {
+        let tabs = Arc::new(Semaphore::new(max_tabs));
+
+        loop {
+            let permit = tabs.clone().acquire_owned().await.unwrap();
+            let task = scheduler.next().await;
 
-        rendered.sort_by_key(|r| r.order);
+            // Skip documents of requests which were cancelled while queued
+            if task.response.is_closed() {
+                continue;
+            }
 
-        for out in rendered {
-            let _ = cmd.response.send(out.resp).await;
+            handle_req(
+                config.clone(),
+                browsers.clone(),
+                cache.clone(),
+                task,
+                permit,
+            );
         }
     });
+
+    Ok(())
+}
+
+// Answers a document without queueing it. The reply is sent by its own task, so a client
+// which does not consume its responses can't block the intake of other requests.
+fn reply(task: RenderTask, resp: InternalResponse, cached: bool) {
+    tokio::spawn(async move {
+        let _ = task
+            .response
+            .send(RendererResponse {
+                resp,
+                order: task.order,
+                cached,
+            })
+            .await;
+    });
+}
+
+// Limits below one would never let a document render
+fn at_least_one(config: &Config, key: &str, default: i64) -> usize {
+    let value = config.get_int(key).unwrap_or(default);
+    if value < 1 {
+        panic!("{} must be at least 1, got {}", key, value);
+    }
+
+    value as usize
 }
 
 pub fn handle_req(
-    tab: Arc<Tab>,
-    req: &RenderData,
-    tx: mpsc::Sender<RendererResponse>,
-    order: usize,
+    config: Config,
+    browsers: Arc<BrowserPool>,
+    cache: Option<Arc<dyn RenderCache>>,
+    task: RenderTask,
+    permit: OwnedSemaphorePermit,
 ) {
-    let req2 = req.clone();
     tokio::spawn(async move {
-        let content = req2
-            .clone()
-            .source
-            .expect("no source")
-            .content
-            .expect("no content");
-        let options = req2.clone().options;
-        let out = content_to_pdf(tab, content, options.clone());
-        let _ = tx.clone().send(RendererResponse { resp: out, order }).await;
+        let out = match Proxy::resolve(
+            &config,
+            task.data.options.as_ref().and_then(|o| o.proxy.as_ref()),
+        ) {
+            Ok(proxy) => render_with_retries(&config, &browsers, &task, proxy.as_ref()).await,
+            Err(err) => Err(err),
+        };
+
+        // Release the tab before waiting for the response to be consumed
+        drop(permit);
+
+        // Stored in the background, so writing the cache never delays the response
+        if let (Some(cache), Some(key), Ok(pdf)) = (cache, task.cache_key.clone(), &out) {
+            let pdf = pdf.clone();
+            tokio::spawn(async move { cache.put(&key, pdf).await });
+        }
+
+        let _ = task
+            .response
+            .send(RendererResponse {
+                resp: out,
+                order: task.order,
+                cached: false,
+            })
+            .await;
+    });
+}
+
+async fn render_with_retries(
+    config: &Config,
+    browsers: &BrowserPool,
+    task: &RenderTask,
+    proxy: Option<&Proxy>,
+) -> InternalResponse {
+    let max_retries = config.get_int("renderer.browser.max_retries").unwrap_or(2) as usize;
+
+    let mut attempt = 0;
+    loop {
+        // Waiting for a browser is given up once the caller is gone
+        let lease = tokio::select! {
+            lease = browsers.acquire(proxy) => lease?,
+            _ = task.response.closed() => return Err(RenderError::Cancelled),
+        };
+        let out = render(config, &lease.instance, task, proxy).await;
+
+        // Documents failing because the browser crashed are retried on a new one
+        if out.is_err() && !browsers.is_alive(&lease.instance).await {
+            browsers.restart(lease).await;
+
+            if attempt < max_retries {
+                attempt += 1;
+                warn!(
+                    "browser crashed while rendering, retrying document ({}/{})",
+                    attempt, max_retries
+                );
+                continue;
+            }
+        } else {
+            browsers.release(lease).await;
+        }
+
+        return out;
+    }
+}
+
+// Looks up a document in the cache, remembering its key to store the rendered result
+async fn lookup(cache: &dyn RenderCache, task: &mut RenderTask) -> Option<Vec<u8>> {
+    if task.cache_control == CacheControl::Bypass {
+        return None;
+    }
+
+    task.cache_key = cache_key(&task.data, &task.assets);
+
+    match task.cache_control {
+        CacheControl::Use => cache.get(task.cache_key.as_ref()?).await,
+        _ => None,
+    }
+}
+
+async fn render(
+    config: &Config,
+    instance: &Arc<Instance>,
+    task: &RenderTask,
+    proxy: Option<&Proxy>,
+) -> Result<Vec<u8>, RenderError> {
+    let source = task
+        .data
+        .source
+        .clone()
+        .filter(|s| s.content.is_some())
+        .ok_or_else(|| RenderError::InvalidInput("missing source".to_string()))?;
+    let options = task.data.options.clone();
+
+    let templates = resolve_templates(config, options.clone(), proxy)
+        .await
+        .map_err(|err| {
+            err.downcast::<RenderError>().unwrap_or_else(|err| {
+                RenderError::Upstream(format!("failed fetching template: {}", err))
+            })
+        })?;
+    // Documents with credentials get their own context, so neither their cookies nor
+    // the session cookies set in reply to their headers or basic auth are shared with
+    // other documents. The context is disposed together with the tab.
+    let mut target = RenderTarget {
+        instance: instance.clone(),
+        context: None,
+        tab: None,
+    };
+
+    if !source.cookies.is_empty() || !source.headers.is_empty() || source.basic_auth.is_some() {
+        let context = instance
+            .browser
+            .new_context()
+            .map_err(|err| RenderError::Internal(format!("failed creating context: {}", err)))?;
+        target.context = Some(context.get_id().to_string());
+    }
+
+    let tab = match &target.context {
+        Some(id) => Context::new(&instance.browser, id.clone()).new_tab(),
+        None => instance.browser.new_tab(),
+    }
+    .map_err(|err| RenderError::Internal(format!("failed opening tab: {}", err)))?;
+    target.tab = Some(tab.clone());
+
+    if let Some(proxy) = proxy.filter(|p| p.username.is_some()) {
+        answer_proxy_auth(&tab, proxy)?;
+    }
+
+    let phases = Arc::new(PhaseTracker::new(
+        config,
+        options.as_ref().and_then(|o| o.timeouts.as_ref()),
+    ));
+
+    let rendering = tokio::task::spawn_blocking({
+        let (config, tab, phases) = (config.clone(), tab.clone(), phases.clone());
+        let assets = task.assets.clone();
+        move || content_to_pdf(&config, tab, source, options, templates, assets, &phases)
     });
+
+    let err = tokio::select! {
+        out = rendering => {
+            return out
+                .map_err(|err| RenderError::Internal(err.to_string()))?
+                .map_err(|err| phases.timed_out().unwrap_or(err));
+        }
+        timeout = phases.expired() => timeout,
+        // The response receiver is dropped once the gRPC call was cancelled or exceeded its deadline
+        _ = task.response.closed() => RenderError::Cancelled,
+    };
+
+    // Closing the tab by its render target makes pending browser calls of the aborted render fail
+    phases.abort();
+
+    Err(err)
+}
+
+// Closes the tab and disposes its context once rendering finished, failed or was aborted
+struct RenderTarget {
+    instance: Arc<Instance>,
+    context: Option<String>,
+    tab: Option<Arc<Tab>>,
+}
+
+impl Drop for RenderTarget {
+    fn drop(&mut self) {
+        let (instance, context, tab) =
+            (self.instance.clone(), self.context.take(), self.tab.take());
+
+        tokio::task::spawn_blocking(move || {
+            if let Some(Err(err)) = tab.map(|tab| tab.close(false)) {
+                warn!("failed closing tab: {}", err);
+            }
+
+            if let Some(Err(err)) = context.map(|id| instance.dispose_context(id)) {
+                warn!("failed disposing browser context: {}", err);
+            }
+        });
+    }
 }
@@ -0,0 +1,199 @@
+use crate::proto::pdf_rendering::render_request::Type;
+use crate::proto::pdf_rendering::render_source::Content;
+use crate::proto::pdf_rendering::{RenderData, RenderRequest};
+use chrono::{SecondsFormat, Utc};
+use config::Config;
+use log::{debug, info, log_enabled, Level, Record};
+use prost::Message;
+use serde_json::{json, Value};
+use std::io::Write;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use ulid::Ulid;
+
+const REDACTED: &str = "[REDACTED]";
+
+// Logs a summary of the request and, on debug level, the request itself with credentials
+// redacted and large payloads like HTML sources or attachments truncated.
+// Requests are logged once authenticated, so the subject is the verified one.
+pub fn log_request(config: &Config, id: Ulid, kind: &str, request: &RenderRequest) {
+    let data = documents(request);
+    let urls = data
+        .iter()
+        .filter(|d| matches!(content(d), Some(Content::Url(_))))
+        .count();
+    let html = data
+        .iter()
+        .filter(|d| matches!(content(d), Some(Content::Html(_))))
+        .count();
+    let subject = request
+        .subject
+        .as_ref()
+        .and_then(|s| s.id.clone())
+        .unwrap_or("-".to_string());
+
+    info!(
+        "[{}] {}: {} documents ({} url, {} html), {} bytes, subject {}",
+        id,
+        kind,
+        data.len(),
+        urls,
+        html,
+        request.encoded_len(),
+        subject
+    );
+
+    if log_enabled!(Level::Debug) {
+        debug!(
+            "[{}] {} details: {}",
+            id,
+            kind,
+            loggable(config, &redact_request(request))
+        );
+    }
+}
+
+fn documents(request: &RenderRequest) -> Vec<&RenderData> {
+    match &request.r#type {
+        Some(Type::Individual(individual)) => individual
+            .data
+            .iter()
+            .filter_map(|d| d.data.as_ref())
+            .collect(),
+        Some(Type::Combined(combined)) => combined.data.iter().collect(),
+        None => vec![],
+    }
+}
+
+fn content(data: &RenderData) -> Option<&Content> {
+    data.source.as_ref().and_then(|s| s.content.as_ref())
+}
+
+// Copies a request for logging, without the credentials of its documents, proxies and subject
+pub fn redact_request(request: &RenderRequest) -> RenderRequest {
+    let mut request = request.clone();
+
+    if let Some(subject) = request.subject.as_mut() {
+        if subject.token.is_some() {
+            subject.token = Some(REDACTED.to_string());
+        }
+    }
+
+    match request.r#type.as_mut() {
+        Some(Type::Individual(individual)) => individual
+            .data
+            .iter_mut()
+            .filter_map(|d| d.data.as_mut())
+            .for_each(redact_data),
+        Some(Type::Combined(combined)) => combined.data.iter_mut().for_each(redact_data),
+        None => {}
+    }
+
+    request
+}
+
+fn redact_data(data: &mut RenderData) {
+    if let Some(source) = data.source.as_mut() {
+        for header in source.headers.iter_mut() {
+            header.value = REDACTED.to_string();
+        }
+
+        for cookie in source.cookies.iter_mut() {
+            cookie.value = REDACTED.to_string();
+        }
+
+        if let Some(auth) = source.basic_auth.as_mut() {
+            auth.password = REDACTED.to_string();
+        }
+    }
+
+    if let Some(proxy) = data.options.as_mut().and_then(|o| o.proxy.as_mut()) {
+        if proxy.password.is_some() {
+            proxy.password = Some(REDACTED.to_string());
+        }
+    }
+}
+
+pub fn redact_metadata(metadata: &MetadataMap) -> MetadataMap {
+    let mut metadata = metadata.clone();
+
+    for key in ["authorization", "proxy-authorization", "cookie"] {
+        if metadata.contains_key(key) {
+            metadata.insert(key, MetadataValue::from_static(REDACTED));
+        }
+    }
+
+    metadata
+}
+
+// Serializes a message to JSON, redacting fields named in `logger.requests.redact`
+// and truncating strings and byte arrays longer than `logger.requests.max_length`
+pub fn loggable<T: serde::Serialize>(config: &Config, message: &T) -> String {
+    let max_length = config.get_int("logger.requests.max_length").unwrap_or(256) as usize;
+    let redact: Vec<String> = config
+        .get_array("logger.requests.redact")
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|v| v.into_string().ok())
+        .collect();
+
+    let mut value = serde_json::to_value(message).unwrap_or(Value::Null);
+    sanitize(&mut value, max_length, &redact);
+    value.to_string()
+}
+
+fn sanitize(value: &mut Value, max_length: usize, redact: &[String]) {
+    match value {
+        Value::String(s) if s.len() > max_length => {
+            let mut end = max_length;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            *s = format!("{}... ({} bytes)", &s[..end], s.len());
+        }
+        // Bytes are serialized as arrays of numbers
+        Value::Array(items) if items.len() > max_length && items.iter().all(Value::is_number) => {
+            *value = Value::String(format!("<{} bytes>", items.len()));
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| sanitize(item, max_length, redact)),
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if redact.iter().any(|r| r.eq_ignore_ascii_case(name)) && !field.is_null() {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    sanitize(field, max_length, redact);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// Writes log records as JSON lines, a leading `[<request id>]` of the message is
+// moved into the `request_id` field
+pub fn json_format(buf: &mut env_logger::fmt::Formatter, record: &Record) -> std::io::Result<()> {
+    let message = record.args().to_string();
+
+    let (request_id, message) = match message
+        .strip_prefix('[')
+        .and_then(|m| m.split_once("] "))
+        .filter(|(id, _)| Ulid::from_string(id).is_ok())
+    {
+        Some((id, rest)) => (Some(id.to_string()), rest.to_string()),
+        None => (None, message),
+    };
+
+    let mut entry = json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": message,
+    });
+
+    if let Some(id) = request_id {
+        entry["request_id"] = json!(id);
+    }
+
+    writeln!(buf, "{}", entry)
+}
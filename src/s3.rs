@@ -2,24 +2,26 @@ use crate::proto::attribute::Attribute;
 use crate::proto::auth::Subject;
 use crate::proto::meta;
 use crate::proto::pdf_rendering::UploadOptions;
-use crate::proto::user::user_service_client::UserServiceClient;
-use crate::proto::user::FindByTokenRequest;
 use aws_sdk_s3::config::Credentials;
-use aws_sdk_s3::error::SdkError;
-use aws_sdk_s3::operation::put_object::{PutObjectError, PutObjectOutput};
+use aws_sdk_s3::presigning::PresigningConfig;
 use aws_sdk_s3::primitives::ByteStream;
-use aws_smithy_runtime_api::client::orchestrator::HttpResponse;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use bytes::Bytes;
 use config::Config;
+use log::{error, warn};
 use serde::Serialize;
 use serde_json::json;
-use std::time::SystemTime;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
-pub async fn upload_to_s3(
-    config: Config,
-    upload_opt: UploadOptions,
-    data: Vec<u8>,
-    subject: Option<Subject>,
-) -> Result<PutObjectOutput, SdkError<PutObjectError, HttpResponse>> {
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// Created once, the client keeps a pool of connections shared by all uploads
+pub fn create_client(config: &Config) -> aws_sdk_s3::Client {
     let endpoint = config.get_string("s3.client.endpoint").unwrap();
     let region = config.get_string("s3.client.region").unwrap();
     let access_key = config.get_string("s3.client.access_key").unwrap();
@@ -39,64 +41,313 @@ pub async fn upload_to_s3(
         )
         .build();
 
-    let client = aws_sdk_s3::Client::from_conf(s3_config);
+    aws_sdk_s3::Client::from_conf(s3_config)
+}
 
-    let bucket_name = upload_opt.bucket.unwrap();
-    let key = upload_opt.key.unwrap();
-    let meta = create_metadata(config.clone(), subject.clone());
-    let mut subject_value = "{}".to_owned();
-
-    if subject.clone().is_some() && subject.clone().unwrap().token.is_some() {
-        let mut ids_client =
-            UserServiceClient::connect(config.get_string("client.user.address").unwrap())
-                .await
-                .unwrap();
-
-        let response = ids_client
-            .find_by_token(tonic::Request::new(FindByTokenRequest {
-                token: subject.clone().unwrap().token,
-            }))
-            .await
-            .unwrap();
-
-        match response.get_ref().clone().payload {
-            None => {}
-            Some(user) => {
-                subject_value = json!({
-                  "id": user.id.unwrap()
-                })
-                .to_string();
+pub fn object_url(config: &Config, bucket: &str, key: &str) -> String {
+    let endpoint = config.get_string("s3.client.endpoint").unwrap();
+    let endpoint = endpoint.trim_end_matches('/');
+    let key = encode_key(key);
+
+    if config
+        .get_bool("s3.client.s3_force_path_style")
+        .unwrap_or(false)
+    {
+        return format!("{}/{}/{}", endpoint, bucket, key);
+    }
+
+    match endpoint.split_once("://") {
+        Some((scheme, host)) => format!("{}://{}.{}/{}", scheme, bucket, host, key),
+        None => format!("{}.{}/{}", bucket, endpoint, key),
+    }
+}
+
+pub async fn presigned_url(
+    config: &Config,
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    if !config.get_bool("s3.presign.enabled").unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let expires_in = config.get_int("s3.presign.expires_in").unwrap_or(3600) as u64;
+
+    let request = client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .presigned(PresigningConfig::expires_in(Duration::from_secs(
+            expires_in,
+        ))?)
+        .await?;
+
+    Ok(Some(request.uri().to_string()))
+}
+
+// Percent-encode everything except unreserved characters and path separators
+fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
             }
-        }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub struct Uploaded {
+    pub etag: Option<String>,
+    pub version_id: Option<String>,
+}
+
+pub async fn upload_to_s3(
+    config: &Config,
+    client: &aws_sdk_s3::Client,
+    upload_opt: UploadOptions,
+    data: Vec<u8>,
+    subject: Option<Subject>,
+) -> Result<Uploaded, Box<dyn Error + Send + Sync>> {
+    let bucket_name = upload_opt.bucket.ok_or("missing bucket")?;
+    let key = upload_opt.key.ok_or("missing key")?;
+    let meta = create_metadata(config.clone(), subject.clone());
+    // The subject was already resolved by the identity service when the request was received
+    let subject_value = match subject.and_then(|s| s.id) {
+        Some(id) => json!({ "id": id }).to_string(),
+        None => "{}".to_owned(),
+    };
+
+    let metadata = HashMap::from([
+        ("Data".to_string(), "{}".to_string()),
+        ("Key".to_string(), key.clone()),
+        ("Meta".to_string(), serde_json::to_string(&meta)?),
+        ("Subject".to_string(), subject_value),
+    ]);
+
+    let threshold = config.get_int("s3.multipart.threshold").unwrap_or(16777216) as usize;
+
+    if data.len() >= threshold {
+        let created = client
+            .create_multipart_upload()
+            .bucket(bucket_name.clone())
+            .key(key.clone())
+            .content_type("application/pdf")
+            .set_content_disposition(upload_opt.content_disposition)
+            .set_metadata(Some(metadata))
+            .send()
+            .await?;
+
+        let upload = MultipartUpload {
+            client: client.clone(),
+            bucket: bucket_name,
+            key,
+            upload_id: created.upload_id().ok_or("missing upload id")?.to_string(),
+        };
+
+        return upload.send(config, data).await;
     }
 
-    client
+    let output = client
         .put_object()
-        .bucket(bucket_name.clone())
-        .key(key.clone())
-        .body(ByteStream::from(data.clone()))
+        .bucket(bucket_name)
+        .key(key)
+        .body(ByteStream::from(data))
         .content_type("application/pdf")
         .set_content_disposition(upload_opt.content_disposition)
-        .metadata("Data", "{}")
-        .metadata("Key", key.clone())
-        .metadata(
-            "Meta",
-            serde_json::to_string(&meta).expect("failed json serialization"),
-        )
-        .metadata("Subject", subject_value)
+        .set_metadata(Some(metadata))
         .send()
-        .await
+        .await?;
+
+    Ok(Uploaded {
+        etag: output.e_tag().map(|t| t.to_string()),
+        version_id: output.version_id().map(|v| v.to_string()),
+    })
+}
+
+#[derive(Clone)]
+struct MultipartUpload {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+}
+
+impl MultipartUpload {
+    // Uploads the parts in parallel and completes the upload, or aborts it if a part
+    // failed or the upload was dropped, so no incomplete upload is left behind
+    async fn send(
+        &self,
+        config: &Config,
+        data: Vec<u8>,
+    ) -> Result<Uploaded, Box<dyn Error + Send + Sync>> {
+        let mut pending = AbortOnDrop(Some(self.clone()));
+
+        let parts = self.send_parts(config, data).await?;
+
+        let completed = self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+
+        pending.0 = None;
+
+        Ok(Uploaded {
+            etag: completed.e_tag().map(|t| t.to_string()),
+            version_id: completed.version_id().map(|v| v.to_string()),
+        })
+    }
+
+    async fn send_parts(
+        &self,
+        config: &Config,
+        data: Vec<u8>,
+    ) -> Result<Vec<CompletedPart>, Box<dyn Error + Send + Sync>> {
+        // S3 requires parts of at least 5 MiB, except for the last one
+        let part_size =
+            (config.get_int("s3.multipart.part_size").unwrap_or(8388608) as usize).max(5242880);
+        let concurrency = config
+            .get_int("s3.multipart.concurrency")
+            .unwrap_or(4)
+            .max(1) as usize;
+        let retries = config.get_int("s3.multipart.max_retries").unwrap_or(3) as u32;
+        let delay =
+            Duration::from_millis(config.get_int("s3.multipart.retry_delay").unwrap_or(200) as u64);
+
+        // Parts are slices of the document, which is not copied
+        let data = Bytes::from(data);
+        let permits = Arc::new(Semaphore::new(concurrency));
+        let mut tasks = JoinSet::new();
+        let mut parts = Vec::new();
+
+        for (index, start) in (0..data.len()).step_by(part_size).enumerate() {
+            let permit = permits.clone().acquire_owned().await?;
+
+            // Stop starting parts once one has failed
+            while let Some(finished) = tasks.try_join_next() {
+                parts.push(finished??);
+            }
+
+            let upload = self.clone();
+            let part = data.slice(start..(start + part_size).min(data.len()));
+            tasks.spawn(async move {
+                let _permit = permit;
+                upload
+                    .send_part(index as i32 + 1, part, retries, delay)
+                    .await
+            });
+        }
+
+        while let Some(finished) = tasks.join_next().await {
+            parts.push(finished??);
+        }
+
+        parts.sort_by_key(|part| part.part_number());
+
+        Ok(parts)
+    }
+
+    // Retries a failed part with exponential backoff
+    async fn send_part(
+        &self,
+        number: i32,
+        part: Bytes,
+        retries: u32,
+        delay: Duration,
+    ) -> Result<CompletedPart, Box<dyn Error + Send + Sync>> {
+        let mut attempt = 0;
+
+        loop {
+            let sent = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .upload_id(&self.upload_id)
+                .part_number(number)
+                .body(ByteStream::from(part.clone()))
+                .send()
+                .await;
+
+            match sent {
+                Ok(output) => {
+                    return Ok(CompletedPart::builder()
+                        .part_number(number)
+                        .set_e_tag(output.e_tag().map(|t| t.to_string()))
+                        .build());
+                }
+                Err(err) if attempt < retries => {
+                    let backoff = 2u32
+                        .checked_pow(attempt)
+                        .and_then(|factor| delay.checked_mul(factor))
+                        .unwrap_or(MAX_BACKOFF)
+                        .min(MAX_BACKOFF);
+                    attempt += 1;
+                    warn!(
+                        "failed uploading part {} of {}/{}, retrying in {}ms ({}/{}): {}",
+                        number,
+                        self.bucket,
+                        self.key,
+                        backoff.as_millis(),
+                        attempt,
+                        retries,
+                        err
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    async fn abort(&self) {
+        let aborted = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .send()
+            .await;
+
+        if let Err(err) = aborted {
+            error!(
+                "failed aborting multipart upload of {}/{}: {}",
+                self.bucket, self.key, err
+            );
+        }
+    }
+}
+
+struct AbortOnDrop(Option<MultipartUpload>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if let Some(upload) = self.0.take() {
+            tokio::spawn(async move { upload.abort().await });
+        }
+    }
 }
 
 #[derive(Debug, Default, Serialize)]
 pub struct Resource {
-    id: String,
-    key: String,
-    bucket: String,
-    meta: meta::Meta,
+    pub id: String,
+    pub key: String,
+    pub bucket: String,
+    pub meta: meta::Meta,
 }
 
-fn create_metadata(config: Config, subject: Option<Subject>) -> meta::Meta {
+pub fn create_metadata(config: Config, subject: Option<Subject>) -> meta::Meta {
     let mut out = meta::Meta::default();
 
     let now: prost_wkt_types::Timestamp = SystemTime::now().into();
@@ -0,0 +1,106 @@
+use crate::proto::pdf_rendering::{CacheControl, RenderData};
+use crate::types::{Assets, InternalRequest, RendererResponse};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+
+pub struct RenderTask {
+    pub data: RenderData,
+    pub assets: Arc<Assets>,
+    pub response: mpsc::Sender<RendererResponse>,
+    pub order: usize,
+    pub cache_control: CacheControl,
+    pub cache_key: Option<String>,
+}
+
+impl RenderTask {
+    pub fn split(cmd: InternalRequest) -> Vec<RenderTask> {
+        cmd.data
+            .into_iter()
+            .enumerate()
+            .map(|(order, data)| RenderTask {
+                data,
+                assets: cmd.assets.clone(),
+                response: cmd.response.clone(),
+                order,
+                cache_control: cmd.cache_control,
+                cache_key: None,
+            })
+            .collect()
+    }
+}
+
+struct Queues {
+    requests: VecDeque<VecDeque<RenderTask>>,
+    queued: usize,
+}
+
+// Schedules documents round-robin across requests, so a single large request
+// can't starve requests which are submitted after it.
+pub struct Scheduler {
+    max_queued: usize,
+    queues: Mutex<Queues>,
+    notify: Notify,
+}
+
+impl Scheduler {
+    pub fn new(max_queued: usize) -> Self {
+        Scheduler {
+            max_queued,
+            queues: Mutex::new(Queues {
+                requests: VecDeque::new(),
+                queued: 0,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn push(&self, tasks: Vec<RenderTask>) -> Result<(), Vec<RenderTask>> {
+        let mut queues = self.queues.lock().unwrap();
+
+        if queues.queued + tasks.len() > self.max_queued {
+            return Err(tasks);
+        }
+
+        let tasks = VecDeque::from(tasks);
+
+        if tasks.is_empty() {
+            return Ok(());
+        }
+
+        queues.queued += tasks.len();
+        queues.requests.push_back(tasks);
+
+        drop(queues);
+        self.notify.notify_one();
+
+        Ok(())
+    }
+
+    pub async fn next(&self) -> RenderTask {
+        loop {
+            if let Some(task) = self.pop() {
+                return task;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    fn pop(&self) -> Option<RenderTask> {
+        let mut queues = self.queues.lock().unwrap();
+
+        let mut tasks = queues.requests.pop_front()?;
+        let task = tasks.pop_front();
+
+        if !tasks.is_empty() {
+            queues.requests.push_back(tasks);
+        }
+
+        if task.is_some() {
+            queues.queued -= 1;
+        }
+
+        task
+    }
+}
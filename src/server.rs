@@ -1,27 +1,48 @@
-use crate::pdf_utils::{add_pdf_metadata, merge_pdfs};
+use crate::auth::Auth;
+use crate::events::{Event, EventSink};
+use crate::jobs::{Job, Jobs};
+use crate::pdf_utils::{add_attachments, add_pdf_metadata, merge_pdfs, page_count};
+use crate::pdfa::convert_to_pdfa;
 use crate::proto::auth::Subject;
 use crate::proto::pdf_rendering::info_response::ChromeVersion;
+use crate::proto::pdf_rendering::job_response::State;
 use crate::proto::pdf_rendering::pdf_rendering_service_server::PdfRenderingService;
 use crate::proto::pdf_rendering::render_request::Type;
+use crate::proto::pdf_rendering::render_source::Content;
+use crate::proto::pdf_rendering::render_upload_request::Part;
 use crate::proto::pdf_rendering::{
     rendering_response, response_payload, CombinedRequest, IndividualRequest, IndividualResponse,
-    InfoResponse, OutputOptions, RenderRequest, RenderingResponse, ResponsePayload,
-    ResponsePayloadWithStatus, ResponsePdf, ResponseS3Upload,
+    InfoResponse, JobRequest, JobResponse, OutputOptions, RenderData, RenderRequest, RenderSource,
+    RenderStreamResponse, RenderUploadHeader, RenderUploadRequest, RenderingResponse,
+    ResponsePayload, ResponsePayloadWithStatus, ResponsePdf, ResponseS3Upload, UploadOptions,
 };
 use crate::proto::status;
 use crate::proto::status::OperationStatus;
-use crate::s3::upload_to_s3;
-use crate::types::{IDExtension, InternalRequest, InternalResponse};
+use crate::request_log::log_request;
+use crate::storage::StorageBackend;
+use crate::types::{
+    Asset, Assets, IDExtension, InternalRequest, InternalResponse, RenderError, RendererResponse,
+};
 use config::Config;
-use log::{debug, error, info};
+use log::{error, info};
 use lopdf::Document;
 use prost_wkt_types::Empty;
+use std::pin::Pin;
+use std::sync::Arc;
 use tokio::sync::mpsc;
-use tonic::{Request, Response, Status};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Code, Request, Response, Status, Streaming};
+use ulid::Ulid;
 
+#[derive(Clone)]
 pub struct PDFServer {
     pub config: Config,
     pub renderer: mpsc::Sender<InternalRequest>,
+    pub storage: Arc<dyn StorageBackend>,
+    pub events: Arc<dyn EventSink>,
+    pub jobs: Arc<Jobs>,
+    pub auth: Arc<Auth>,
 }
 
 #[tonic::async_trait]
@@ -30,64 +51,328 @@ impl PdfRenderingService for PDFServer {
         &self,
         request: Request<RenderRequest>,
     ) -> Result<Response<RenderingResponse>, Status> {
-        let (tx, mut rx) = mpsc::channel::<InternalResponse>(32);
+        let id = request.extensions().get::<IDExtension>().unwrap().id;
+
+        let mut request = request.into_inner();
+        self.authenticate(&mut request).await?;
+
+        log_request(&self.config, id, "Rendering request", &request);
+
+        self.process(id, request).await
+    }
+
+    async fn submit_render(
+        &self,
+        request: Request<RenderRequest>,
+    ) -> Result<Response<JobResponse>, Status> {
+        let id = request.extensions().get::<IDExtension>().unwrap().id;
+
+        let mut req = request.into_inner();
+        render_data(&req.r#type).map_err(Status::invalid_argument)?;
+        self.authenticate(&mut req).await?;
+
+        log_request(&self.config, id, "Submitting rendering job", &req);
+
+        let owner = req.subject.as_ref().and_then(|s| s.id.clone());
+        self.jobs.submit(id.to_string(), owner).await;
+
+        let server = self.clone();
+        let handle = self.jobs.spawn(id.to_string(), async move {
+            server.jobs.start(id.to_string()).await;
+            server.process(id, req).await.map(|r| r.into_inner())
+        });
+
+        let jobs = self.jobs.clone();
+        tokio::spawn(async move {
+            match handle.await {
+                Ok(Ok(result)) => jobs.finish(id.to_string(), State::Done, Some(result)).await,
+                Ok(Err(status)) => {
+                    let result = RenderingResponse {
+                        operation_status: Some(OperationStatus {
+                            code: Some(http_code(status.code())),
+                            message: Some(status.message().to_string()),
+                        }),
+                        response: None,
+                    };
+                    jobs.finish(id.to_string(), State::Failed, Some(result))
+                        .await
+                }
+                Err(err) if err.is_cancelled() => {
+                    jobs.finish(id.to_string(), State::Cancelled, None).await
+                }
+                Err(err) => {
+                    error!("[{}] Rendering job failed: {}", id, err);
+                    jobs.finish(id.to_string(), State::Failed, None).await
+                }
+            }
+        });
+
+        match self.jobs.store.get(&id.to_string()).await {
+            Some(job) => Ok(Response::new(job.response)),
+            None => Err(Status::internal("failed storing job")),
+        }
+    }
 
-        let id = request.extensions().get::<IDExtension>().unwrap();
+    async fn get_job(&self, request: Request<JobRequest>) -> Result<Response<JobResponse>, Status> {
+        let job = self.owned_job(request.into_inner()).await?;
+        Ok(Response::new(job.response))
+    }
 
-        debug!("[{}] Rendering request: {:?}", id.id, request.get_ref());
+    async fn cancel_job(
+        &self,
+        request: Request<JobRequest>,
+    ) -> Result<Response<JobResponse>, Status> {
+        let job = self.owned_job(request.into_inner()).await?;
+
+        match self.jobs.cancel(&job.response.id).await {
+            Some(job) => Ok(Response::new(job.response)),
+            None => Err(Status::not_found("job not found")),
+        }
+    }
+
+    type RenderStreamStream =
+        Pin<Box<dyn Stream<Item = Result<RenderStreamResponse, Status>> + Send>>;
+
+    async fn render_stream(
+        &self,
+        request: Request<RenderRequest>,
+    ) -> Result<Response<Self::RenderStreamStream>, Status> {
+        let (tx, mut rx) = mpsc::channel::<RendererResponse>(32);
 
-        let data = match request.get_ref().clone().r#type.unwrap() {
-            Type::Individual(req) => req.data.iter().map(|x| x.clone().data.unwrap()).collect(),
-            Type::Combined(req) => req.data,
+        let id = request.extensions().get::<IDExtension>().unwrap().id;
+
+        let mut request = request.into_inner();
+
+        let req = match request.r#type.clone() {
+            Some(Type::Individual(req)) => req,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "streaming is only supported for individual requests",
+                ))
+            }
         };
+        let data = render_data(&request.r#type).map_err(Status::invalid_argument)?;
+
+        self.authenticate(&mut request).await?;
+        let subject = request.subject.clone();
 
-        match self
-            .renderer
+        log_request(&self.config, id, "Streaming rendering request", &request);
+
+        self.renderer
             .send(InternalRequest {
                 response: tx,
-                data: data.clone(),
+                data,
+                assets: Default::default(),
+                cache_control: request.cache_control(),
             })
             .await
-        {
-            Ok(_) => {}
-            Err(err) => {
+            .map_err(|err| {
                 error!("error sending rendering request: {}", err);
+                Status::unavailable("renderer unavailable")
+            })?;
+
+        let (out_tx, out_rx) = mpsc::channel::<Result<RenderStreamResponse, Status>>(32);
+        let server = self.clone();
+
+        // Documents are processed and sent in the order they finish rendering. Once the client
+        // is gone the renderer receiver is dropped, which closes the tabs of pending documents.
+        tokio::spawn(async move {
+            loop {
+                let rendered = tokio::select! {
+                    rendered = rx.recv() => rendered,
+                    _ = out_tx.closed() => break,
+                };
+                let Some(rendered) = rendered else {
+                    info!("[{}] Rendering success", id);
+                    return;
+                };
+
+                if is_queue_full(&rendered.resp) {
+                    let _ = out_tx
+                        .send(Err(Status::resource_exhausted("render queue is full")))
+                        .await;
+                    return;
+                }
+
+                let output = req.data[rendered.order].output.clone();
+                let mut response = tokio::select! {
+                    response = server.document_response(
+                        id,
+                        rendered.order,
+                        Some(rendered.resp),
+                        output,
+                        subject.clone(),
+                    ) => response,
+                    _ = out_tx.closed() => break,
+                };
+                response.cache_hit = Some(rendered.cached);
+
+                let message = RenderStreamResponse {
+                    index: rendered.order as i32,
+                    response: Some(response),
+                };
+
+                if out_tx.send(Ok(message)).await.is_err() {
+                    break;
+                }
             }
-        }
 
-        let mut rendered = Vec::with_capacity(data.len());
+            info!("[{}] Stream closed by client", id);
+        });
 
-        for _ in data.iter() {
-            rendered.push(rx.recv().await);
+        Ok(Response::new(Box::pin(ReceiverStream::new(out_rx))))
+    }
+
+    async fn render_upload(
+        &self,
+        request: Request<Streaming<RenderUploadRequest>>,
+    ) -> Result<Response<RenderingResponse>, Status> {
+        let (tx, mut rx) = mpsc::channel::<RendererResponse>(1);
+
+        let id = request.extensions().get::<IDExtension>().unwrap().id;
+        let limit = self
+            .config
+            .get_int("server.upload_size_limit")
+            .unwrap_or(1073741824) as usize;
+
+        let mut stream = request.into_inner();
+        let mut header = None;
+        let mut html = Vec::new();
+        let mut assets = Assets::new();
+        let mut total = 0;
+
+        while let Some(message) = stream.message().await? {
+            // Nothing is buffered for callers which haven't been verified yet
+            if header.is_none()
+                && matches!(message.part, Some(Part::Html(_) | Part::Asset(_)))
+                && self.auth.required()
+            {
+                return Err(Status::failed_precondition("header must be sent first"));
+            }
+
+            match message.part {
+                None => {}
+                // Callers are verified before their HTML and assets are received
+                Some(Part::Header(mut h)) => {
+                    h.subject = self.auth.authenticate(h.subject.take()).await?;
+
+                    let uploads: Vec<UploadOptions> = h
+                        .output
+                        .iter()
+                        .filter_map(|o| o.upload_options.clone())
+                        .collect();
+                    self.auth.authorize(h.subject.as_ref(), &uploads).await?;
+
+                    header = Some(h);
+                }
+                Some(Part::Html(chunk)) => {
+                    total += chunk.len();
+                    html.extend(chunk);
+                }
+                Some(Part::Asset(chunk)) => {
+                    total += chunk.data.len();
+                    let path = chunk.path.trim_start_matches('/').to_string();
+                    let content_type = chunk
+                        .content_type
+                        .unwrap_or_else(|| guess_content_type(&path).to_string());
+
+                    assets
+                        .entry(path)
+                        .or_insert_with(|| Asset {
+                            content_type,
+                            data: Vec::new(),
+                        })
+                        .data
+                        .extend(chunk.data);
+                }
+            }
+
+            if total > limit {
+                return Err(Status::resource_exhausted(format!(
+                    "upload exceeds limit of {} bytes",
+                    limit
+                )));
+            }
         }
 
-        info!("[{}] Rendering success", id.id);
-
-        let output = match request.get_ref().clone().r#type.unwrap() {
-            Type::Individual(req) => Ok(Self::individual_response(
-                req,
-                self.config.clone(),
-                rendered,
-                request.get_ref().clone().subject,
-            )
-            .await),
-            Type::Combined(req) => Ok(Self::combined_response(
-                req,
-                self.config.clone(),
-                rendered,
-                request.get_ref().clone().subject,
-            )
-            .await),
+        let header = match header {
+            Some(header) => header,
+            None => RenderUploadHeader {
+                subject: self.auth.authenticate(None).await?,
+                ..Default::default()
+            },
         };
 
-        return output;
+        if html.is_empty() {
+            return Err(Status::invalid_argument("missing html"));
+        }
+
+        let html = String::from_utf8(html)
+            .map_err(|_| Status::invalid_argument("html is not valid UTF-8"))?;
+
+        info!(
+            "[{}] Received upload with {} bytes of HTML and {} assets, subject {}",
+            id,
+            html.len(),
+            assets.len(),
+            header
+                .subject
+                .as_ref()
+                .and_then(|s| s.id.clone())
+                .unwrap_or("-".to_string())
+        );
+
+        self.renderer
+            .send(InternalRequest {
+                response: tx,
+                data: vec![RenderData {
+                    source: Some(RenderSource {
+                        content: Some(Content::Html(html)),
+                        ..Default::default()
+                    }),
+                    options: header.options.clone(),
+                }],
+                assets: Arc::new(assets),
+                cache_control: header.cache_control(),
+            })
+            .await
+            .map_err(|err| {
+                error!("error sending rendering request: {}", err);
+                Status::unavailable("renderer unavailable")
+            })?;
+
+        let rendered = rx
+            .recv()
+            .await
+            .ok_or_else(|| Status::unavailable("renderer unavailable"))?;
+
+        if is_queue_full(&rendered.resp) {
+            return Err(Status::resource_exhausted("render queue is full"));
+        }
+
+        info!("[{}] Rendering success", id);
+
+        let mut response = self
+            .document_response(id, 0, Some(rendered.resp), header.output, header.subject)
+            .await;
+        response.cache_hit = Some(rendered.cached);
+
+        Ok(Response::new(RenderingResponse {
+            operation_status: Some(operation_status(std::slice::from_ref(&response))),
+            response: Some(rendering_response::Response::Individual(
+                IndividualResponse {
+                    rendering_response: vec![response],
+                },
+            )),
+        }))
     }
 
     async fn info(&self, _: Request<Empty>) -> Result<Response<InfoResponse>, Status> {
         let version = headless_chrome::Browser::default()
-            .expect("failed opening browser")
-            .get_version()
-            .expect("failed fetching version");
+            .and_then(|browser| browser.get_version())
+            .map_err(|err| {
+                Status::unavailable(format!("failed fetching browser version: {}", err))
+            })?;
         Ok(Response::new(InfoResponse {
             chrome: Some(ChromeVersion {
                 js_version: version.js_version,
@@ -101,68 +386,115 @@ impl PdfRenderingService for PDFServer {
 }
 
 impl PDFServer {
+    // Jobs of authenticated subjects are only visible to them, others get the same error as unknown jobs
+    async fn owned_job(&self, request: JobRequest) -> Result<Job, Status> {
+        let subject = self.auth.authenticate(request.subject).await?;
+        let owner = subject.and_then(|s| s.id);
+
+        match self.jobs.store.get(&request.id).await {
+            Some(job) if job.owner.is_none() || job.owner == owner => Ok(job),
+            _ => Err(Status::not_found("job not found")),
+        }
+    }
+
+    // Replaces the subject by the verified one and checks that it may create the objects
+    // the documents are uploaded to
+    async fn authenticate(&self, request: &mut RenderRequest) -> Result<(), Status> {
+        request.subject = self.auth.authenticate(request.subject.take()).await?;
+
+        let uploads: Vec<UploadOptions> = match &request.r#type {
+            Some(Type::Individual(req)) => req
+                .data
+                .iter()
+                .filter_map(|d| d.output.as_ref())
+                .filter_map(|o| o.upload_options.clone())
+                .collect(),
+            Some(Type::Combined(req)) => req
+                .output
+                .iter()
+                .filter_map(|o| o.upload_options.clone())
+                .collect(),
+            None => vec![],
+        };
+
+        self.auth
+            .authorize(request.subject.as_ref(), &uploads)
+            .await
+    }
+
+    async fn process(
+        &self,
+        id: Ulid,
+        request: RenderRequest,
+    ) -> Result<Response<RenderingResponse>, Status> {
+        let (tx, mut rx) = mpsc::channel::<RendererResponse>(32);
+
+        let data = render_data(&request.r#type).map_err(Status::invalid_argument)?;
+
+        self.renderer
+            .send(InternalRequest {
+                response: tx,
+                data: data.clone(),
+                assets: Default::default(),
+                cache_control: request.cache_control(),
+            })
+            .await
+            .map_err(|err| {
+                error!("error sending rendering request: {}", err);
+                Status::unavailable("renderer unavailable")
+            })?;
+
+        let mut rendered: Vec<Option<InternalResponse>> = data.iter().map(|_| None).collect();
+        let mut cached = vec![false; data.len()];
+
+        for _ in data.iter() {
+            match rx.recv().await {
+                None => return Err(Status::unavailable("renderer unavailable")),
+                Some(response) => {
+                    rendered[response.order] = Some(response.resp);
+                    cached[response.order] = response.cached;
+                }
+            }
+        }
+
+        if rendered.iter().flatten().any(is_queue_full) {
+            return Err(Status::resource_exhausted("render queue is full"));
+        }
+
+        info!("[{}] Rendering success", id);
+
+        match request.r#type {
+            Some(Type::Individual(req)) => Ok(self
+                .individual_response(id, req, rendered, cached, request.subject)
+                .await),
+            Some(Type::Combined(req)) => Ok(self
+                .combined_response(id, req, rendered, cached, request.subject)
+                .await),
+            None => Err(Status::invalid_argument("missing request type")),
+        }
+    }
+
     async fn individual_response(
+        &self,
+        id: Ulid,
         req: IndividualRequest,
-        config: Config,
         rendered: Vec<Option<InternalResponse>>,
+        cached: Vec<bool>,
         subject: Option<Subject>,
     ) -> Response<RenderingResponse> {
         let mut out = Vec::with_capacity(rendered.len());
 
-        for (i, opt) in rendered.iter().enumerate() {
-            match opt {
-                None => {
-                    out.push(ResponsePayloadWithStatus {
-                        status: Some(status::Status {
-                            id: None,
-                            code: Some(500),
-                            message: Some("unknown error".to_string()),
-                        }),
-                        payload: None,
-                    });
-                }
-                Some(response) => match response {
-                    Err(err) => {
-                        out.push(ResponsePayloadWithStatus {
-                            status: Some(status::Status {
-                                id: None,
-                                code: Some(400),
-                                message: Some(format!("rendering failed: {}", err)),
-                            }),
-                            payload: None,
-                        });
-                    }
-                    Ok(data) => {
-                        let output = req.data[i].output.clone();
-
-                        let mut out_data = data.clone();
-                        if output.is_some() {
-                            out_data = add_pdf_metadata(
-                                out_data.clone(),
-                                output.clone().unwrap().meta_data,
-                            )
-                            .expect("failed adding meta");
-                        }
-
-                        out.push(
-                            Self::construct_response(
-                                config.clone(),
-                                out_data.clone(),
-                                output,
-                                subject.clone(),
-                            )
-                            .await,
-                        )
-                    }
-                },
-            }
+        for (i, opt) in rendered.into_iter().enumerate() {
+            let output = req.data[i].output.clone();
+            let mut response = self
+                .document_response(id, i, opt, output, subject.clone())
+                .await;
+            response.cache_hit = Some(cached[i]);
+            out.push(response);
         }
 
         Response::new(RenderingResponse {
-            operation_status: Some(OperationStatus {
-                code: Some(200),
-                message: Some("success".to_string()),
-            }),
+            operation_status: Some(operation_status(&out)),
             response: Some(rendering_response::Response::Individual(
                 IndividualResponse {
                     rendering_response: out,
@@ -171,90 +503,206 @@ impl PDFServer {
         })
     }
 
+    async fn document_response(
+        &self,
+        id: Ulid,
+        index: usize,
+        rendered: Option<InternalResponse>,
+        output: Option<OutputOptions>,
+        subject: Option<Subject>,
+    ) -> ResponsePayloadWithStatus {
+        let data = match rendered {
+            Some(Ok(data)) => data,
+            Some(Err(err)) => return self.failed(id, index, err, &subject).await,
+            None => {
+                let err = RenderError::Internal("missing render result".to_string());
+                return self.failed(id, index, err, &subject).await;
+            }
+        };
+
+        match post_process(data, &output) {
+            Ok(data) => {
+                self.construct_response(id, index, data, output, subject)
+                    .await
+            }
+            Err(err) => self.failed(id, index, err, &subject).await,
+        }
+    }
+
+    // Reports a document which failed rendering, post-processing or uploading
+    async fn failed(
+        &self,
+        id: Ulid,
+        index: usize,
+        err: RenderError,
+        subject: &Option<Subject>,
+    ) -> ResponsePayloadWithStatus {
+        self.events
+            .emit(Event::RenderFailed {
+                request_id: id.to_string(),
+                index,
+                error: err.to_string(),
+                subject_id: subject.clone().and_then(|s| s.id),
+            })
+            .await;
+
+        failure(&err)
+    }
+
     async fn combined_response(
+        &self,
+        id: Ulid,
         req: CombinedRequest,
-        config: Config,
         rendered: Vec<Option<InternalResponse>>,
+        cached: Vec<bool>,
         subject: Option<Subject>,
     ) -> Response<RenderingResponse> {
-        let mut merged = merge_pdfs(
-            rendered
-                .iter()
-                .map(|x| match x {
-                    None => {
-                        panic!("missing pdf")
+        let mut response = self.combined_payload(id, req, rendered, subject).await;
+
+        // A combined document counts as a cache hit if all of its parts were cached
+        response.cache_hit = Some(cached.iter().all(|c| *c));
+
+        Response::new(RenderingResponse {
+            operation_status: Some(operation_status(std::slice::from_ref(&response))),
+            response: Some(rendering_response::Response::Combined(response)),
+        })
+    }
+
+    // A combined document is only returned if all of its parts rendered, otherwise
+    // the failure of each part is reported
+    async fn combined_payload(
+        &self,
+        id: Ulid,
+        req: CombinedRequest,
+        rendered: Vec<Option<InternalResponse>>,
+        subject: Option<Subject>,
+    ) -> ResponsePayloadWithStatus {
+        let mut documents = Vec::with_capacity(rendered.len());
+        let mut failures = Vec::new();
+
+        for (index, result) in rendered.into_iter().enumerate() {
+            let err = match result {
+                Some(Ok(data)) => match Document::load_mem(&data) {
+                    Ok(document) => {
+                        documents.push(document);
+                        continue;
                     }
-                    Some(r) => match r {
-                        Err(err) => {
-                            panic!("missing pdf: {}", err)
-                        }
-                        Ok(response) => Document::load_mem(response).expect("failed parsing PDF"),
-                    },
+                    Err(err) => RenderError::Unrenderable(format!("failed parsing PDF: {}", err)),
+                },
+                Some(Err(err)) => err,
+                None => RenderError::Internal("missing render result".to_string()),
+            };
+
+            self.events
+                .emit(Event::RenderFailed {
+                    request_id: id.to_string(),
+                    index,
+                    error: err.to_string(),
+                    subject_id: subject.clone().and_then(|s| s.id),
                 })
-                .collect(),
-        )
-        .expect("render failed");
+                .await;
 
-        if req.output.is_some() {
-            merged = add_pdf_metadata(merged, req.output.clone().unwrap().meta_data)
-                .expect("failed adding meta");
+            failures.push((index, err));
         }
 
-        Response::new(RenderingResponse {
-            operation_status: Some(OperationStatus {
-                code: Some(200),
-                message: Some("success".to_string()),
-            }),
-            response: Some(rendering_response::Response::Combined(
-                Self::construct_response(
-                    config.clone(),
-                    merged.clone(),
-                    req.output.clone(),
-                    subject,
-                )
-                .await,
-            )),
-        })
+        if let Some((_, first)) = failures.first() {
+            return ResponsePayloadWithStatus {
+                status: Some(status::Status {
+                    id: None,
+                    code: Some(first.code()),
+                    message: Some(
+                        failures
+                            .iter()
+                            .map(|(index, err)| format!("document {}: {}", index, err))
+                            .collect::<Vec<_>>()
+                            .join("; "),
+                    ),
+                }),
+                payload: None,
+                cache_hit: None,
+            };
+        }
+
+        let merged = merge_pdfs(documents)
+            .map_err(|err| RenderError::Unrenderable(format!("failed merging PDFs: {}", err)))
+            .and_then(|merged| post_process(merged, &req.output));
+
+        match merged {
+            Ok(merged) => {
+                self.construct_response(id, 0, merged, req.output, subject)
+                    .await
+            }
+            Err(err) => self.failed(id, 0, err, &subject).await,
+        }
     }
 
     async fn construct_response(
-        config: Config,
+        &self,
+        id: Ulid,
+        index: usize,
         data: Vec<u8>,
         output: Option<OutputOptions>,
         subject: Option<Subject>,
     ) -> ResponsePayloadWithStatus {
-        if output.clone().is_some() && output.clone().unwrap().upload_options.is_some() {
-            match upload_to_s3(
-                config.clone(),
-                output.unwrap().upload_options.unwrap(),
-                data.clone(),
-                subject,
-            )
-            .await
+        let page_count = page_count(&data);
+        let size = data.len();
+
+        self.events
+            .emit(Event::RenderCompleted {
+                request_id: id.to_string(),
+                index,
+                size,
+                page_count,
+                subject_id: subject.clone().and_then(|s| s.id),
+            })
+            .await;
+
+        if let Some(upload_options) = output.and_then(|o| o.upload_options) {
+            let (bucket, key) = (upload_options.bucket.clone(), upload_options.key.clone());
+
+            match self
+                .storage
+                .upload(upload_options, data, subject.clone())
+                .await
             {
-                Ok(_) => ResponsePayloadWithStatus {
-                    status: Some(status::Status {
-                        id: None,
-                        code: Some(200),
-                        message: Some("success".to_string()),
-                    }),
-                    payload: Some(ResponsePayload {
-                        response: Some(response_payload::Response::UploadResult(
-                            ResponseS3Upload {
-                                length: data.len() as i32,
-                                url: "n/a".to_string(),
-                            },
-                        )),
-                    }),
-                },
-                Err(err) => ResponsePayloadWithStatus {
-                    status: Some(status::Status {
-                        id: None,
-                        code: Some(400),
-                        message: Some(format!("render failed: {}", err)),
-                    }),
-                    payload: None,
-                },
+                Ok(stored) => {
+                    self.events
+                        .emit(Event::ObjectUploaded {
+                            request_id: id.to_string(),
+                            index,
+                            bucket: bucket.unwrap_or_default(),
+                            key: key.unwrap_or_default(),
+                            url: stored.url.clone(),
+                            size,
+                            page_count,
+                            subject_id: subject.and_then(|s| s.id),
+                        })
+                        .await;
+
+                    ResponsePayloadWithStatus {
+                        status: Some(status::Status {
+                            id: None,
+                            code: Some(200),
+                            message: Some("success".to_string()),
+                        }),
+                        payload: Some(ResponsePayload {
+                            response: Some(response_payload::Response::UploadResult(
+                                ResponseS3Upload {
+                                    length: size as i32,
+                                    url: stored.url,
+                                    presigned_url: stored.presigned_url,
+                                    etag: stored.etag,
+                                    version_id: stored.version_id,
+                                },
+                            )),
+                        }),
+                        cache_hit: None,
+                    }
+                }
+                Err(err) => {
+                    let err = RenderError::Upstream(format!("upload failed: {}", err));
+                    self.failed(id, index, err, &subject).await
+                }
             }
         } else {
             ResponsePayloadWithStatus {
@@ -264,11 +712,140 @@ impl PDFServer {
                     message: Some("success".to_string()),
                 }),
                 payload: Some(ResponsePayload {
-                    response: Some(response_payload::Response::Pdf(ResponsePdf {
-                        data: data.clone(),
-                    })),
+                    response: Some(response_payload::Response::Pdf(ResponsePdf { data })),
                 }),
+                cache_hit: None,
             }
         }
     }
 }
+
+// Extracts the documents to render, rejecting malformed requests
+fn render_data(r#type: &Option<Type>) -> Result<Vec<RenderData>, &'static str> {
+    let data: Vec<RenderData> = match r#type {
+        None => return Err("missing request type"),
+        Some(Type::Individual(req)) => req
+            .data
+            .iter()
+            .map(|x| x.data.clone().unwrap_or_default())
+            .collect(),
+        Some(Type::Combined(req)) => req.data.clone(),
+    };
+
+    if data.is_empty() {
+        return Err("no documents to render");
+    }
+
+    Ok(data)
+}
+
+// Aggregates the document statuses, telling complete from partial failures
+fn operation_status(responses: &[ResponsePayloadWithStatus]) -> OperationStatus {
+    let failed: Vec<i32> = responses
+        .iter()
+        .map(|r| r.status.as_ref().and_then(|s| s.code).unwrap_or(500))
+        .filter(|code| *code != 200)
+        .collect();
+
+    if failed.is_empty() {
+        return OperationStatus {
+            code: Some(200),
+            message: Some("success".to_string()),
+        };
+    }
+
+    if failed.len() < responses.len() {
+        return OperationStatus {
+            code: Some(207),
+            message: Some(format!(
+                "{} of {} documents failed",
+                failed.len(),
+                responses.len()
+            )),
+        };
+    }
+
+    // Failures of different kinds can't be summarized by a single specific code
+    let code = if failed.iter().all(|code| *code == failed[0]) {
+        failed[0]
+    } else {
+        500
+    };
+
+    OperationStatus {
+        code: Some(code),
+        message: Some(format!("all {} documents failed", failed.len())),
+    }
+}
+
+fn is_queue_full(response: &InternalResponse) -> bool {
+    matches!(response, Err(RenderError::QueueFull))
+}
+
+fn failure(err: &RenderError) -> ResponsePayloadWithStatus {
+    ResponsePayloadWithStatus {
+        status: Some(status::Status {
+            id: None,
+            code: Some(err.code()),
+            message: Some(err.to_string()),
+        }),
+        payload: None,
+        cache_hit: None,
+    }
+}
+
+// Applies metadata, attachments and PDF/A conversion requested by the output options
+fn post_process(data: Vec<u8>, output: &Option<OutputOptions>) -> Result<Vec<u8>, RenderError> {
+    let Some(output) = output else {
+        return Ok(data);
+    };
+
+    let mut data = add_pdf_metadata(data, output.meta_data.clone())
+        .map_err(|err| RenderError::Unrenderable(format!("failed adding metadata: {}", err)))?;
+
+    data = add_attachments(data, output.attachments.clone())
+        .map_err(|err| RenderError::Unrenderable(format!("failed adding attachments: {}", err)))?;
+
+    if output.generate_pdfa() {
+        data = convert_to_pdfa(data, output.pdfa_conformance(), output.factur_x.as_ref()).map_err(
+            |err| RenderError::Unrenderable(format!("PDF/A conversion failed: {}", err)),
+        )?;
+    }
+
+    Ok(data)
+}
+
+fn http_code(code: Code) -> i32 {
+    match code {
+        Code::Ok => 200,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => 400,
+        Code::Unauthenticated => 401,
+        Code::PermissionDenied => 403,
+        Code::NotFound => 404,
+        Code::AlreadyExists | Code::Aborted => 409,
+        Code::ResourceExhausted => 429,
+        Code::Cancelled => 499,
+        Code::Unimplemented => 501,
+        Code::Unavailable => 503,
+        Code::DeadlineExceeded => 504,
+        _ => 500,
+    }
+}
+
+fn guess_content_type(path: &str) -> &'static str {
+    match path.rsplit('.').next().unwrap_or_default() {
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "html" | "htm" => "text/html",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        _ => "application/octet-stream",
+    }
+}
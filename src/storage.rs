@@ -0,0 +1,160 @@
+use crate::proto::auth::Subject;
+use crate::proto::ostorage::object_service_client::ObjectServiceClient;
+use crate::proto::ostorage::{Object, Options};
+use crate::proto::pdf_rendering::UploadOptions;
+use crate::s3::{create_client, create_metadata, object_url, presigned_url, upload_to_s3};
+use config::Config;
+use log::error;
+use std::error::Error;
+use std::sync::Arc;
+use tonic::transport::{Channel, Endpoint};
+
+pub struct StoredObject {
+    pub url: String,
+    pub presigned_url: Option<String>,
+    pub etag: Option<String>,
+    pub version_id: Option<String>,
+}
+
+#[tonic::async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn upload(
+        &self,
+        upload_opt: UploadOptions,
+        data: Vec<u8>,
+        subject: Option<Subject>,
+    ) -> Result<StoredObject, Box<dyn Error + Send + Sync>>;
+}
+
+pub fn create_storage(config: Config) -> Arc<dyn StorageBackend> {
+    match config
+        .get_string("storage.backend")
+        .unwrap_or("s3".to_string())
+        .as_str()
+    {
+        "ostorage" => {
+            let address = config
+                .get_string("client.ostorage.address")
+                .expect("missing client.ostorage.address");
+
+            let channel = Endpoint::from_shared(address.clone())
+                .unwrap_or_else(|err| panic!("invalid ostorage address {}: {}", address, err))
+                .connect_lazy();
+
+            Arc::new(OstorageStorage {
+                config,
+                client: ObjectServiceClient::new(channel),
+            })
+        }
+        "s3" => Arc::new(S3Storage {
+            client: create_client(&config),
+            config,
+        }),
+        other => panic!("unknown storage backend: {}", other),
+    }
+}
+
+pub struct S3Storage {
+    config: Config,
+    client: aws_sdk_s3::Client,
+}
+
+#[tonic::async_trait]
+impl StorageBackend for S3Storage {
+    async fn upload(
+        &self,
+        upload_opt: UploadOptions,
+        data: Vec<u8>,
+        subject: Option<Subject>,
+    ) -> Result<StoredObject, Box<dyn Error + Send + Sync>> {
+        let bucket = upload_opt.bucket.clone().unwrap_or_default();
+        let key = upload_opt.key.clone().unwrap_or_default();
+
+        let result = upload_to_s3(&self.config, &self.client, upload_opt, data, subject).await?;
+
+        let presigned_url = match presigned_url(&self.config, &self.client, &bucket, &key).await {
+            Ok(url) => url,
+            Err(err) => {
+                error!("failed presigning url for {}/{}: {}", bucket, key, err);
+                None
+            }
+        };
+
+        Ok(StoredObject {
+            url: object_url(&self.config, &bucket, &key),
+            presigned_url,
+            etag: result.etag,
+            version_id: result.version_id,
+        })
+    }
+}
+
+pub struct OstorageStorage {
+    config: Config,
+    client: ObjectServiceClient<Channel>,
+}
+
+#[tonic::async_trait]
+impl StorageBackend for OstorageStorage {
+    async fn upload(
+        &self,
+        upload_opt: UploadOptions,
+        data: Vec<u8>,
+        subject: Option<Subject>,
+    ) -> Result<StoredObject, Box<dyn Error + Send + Sync>> {
+        let chunk_size = self
+            .config
+            .get_int("client.ostorage.chunk_size")
+            .unwrap_or(1048576) as usize;
+
+        let meta = create_metadata(self.config.clone(), subject.clone());
+        let length = data.len() as i64;
+
+        // The object is streamed in chunks, every message carries the subject for ACL checks
+        let messages: Vec<Object> = data
+            .chunks(chunk_size.max(1))
+            .map(|chunk| Object {
+                key: upload_opt.key.clone(),
+                bucket: upload_opt.bucket.clone(),
+                meta: Some(meta.clone()),
+                object: Some(chunk.to_vec()),
+                subject: subject.clone(),
+                options: Some(Options {
+                    content_type: Some("application/pdf".to_string()),
+                    content_disposition: upload_opt.content_disposition.clone(),
+                    length: Some(length),
+                    ..Default::default()
+                }),
+            })
+            .collect();
+
+        let response = self
+            .client
+            .clone()
+            .put(tokio_stream::iter(messages))
+            .await?
+            .into_inner()
+            .response
+            .ok_or("missing ostorage response")?;
+
+        match response.status {
+            Some(status) if status.code.unwrap_or(200) != 200 => {
+                return Err(format!(
+                    "ostorage upload failed: {}",
+                    status.message.unwrap_or_default()
+                )
+                .into());
+            }
+            _ => {}
+        }
+
+        let payload = response.payload.unwrap_or_default();
+
+        Ok(StoredObject {
+            url: payload.url.unwrap_or_default(),
+            presigned_url: None,
+            etag: None,
+            version_id: None,
+        })
+    }
+}
@@ -1,17 +1,98 @@
-use crate::proto::pdf_rendering::RenderData;
+use crate::proto::pdf_rendering::{CacheControl, RenderData};
+use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
 
 pub struct InternalRequest {
     pub data: Vec<RenderData>,
-    pub response: mpsc::Sender<InternalResponse>,
+    pub assets: Arc<Assets>,
+    pub cache_control: CacheControl,
+    pub response: mpsc::Sender<RendererResponse>,
 }
 
-pub type InternalResponse = anyhow::Result<Vec<u8>, Box<dyn Error + Send + Sync>>;
+pub struct Asset {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+pub type Assets = HashMap<String, Asset>;
+
+pub type InternalResponse = Result<Vec<u8>, RenderError>;
 
 pub struct RendererResponse {
     pub resp: InternalResponse,
     pub order: usize,
+    pub cached: bool,
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    // The document or its options are invalid
+    InvalidInput(String),
+    // The document or one of its resources is not allowed to be loaded
+    Forbidden(String),
+    // The document loaded, but could not be rendered or processed
+    Unrenderable(String),
+    // Fetching the document, its templates or storing the result failed
+    Upstream(String),
+    Timeout(String),
+    Cancelled,
+    QueueFull,
+    // The service can't take the document right now
+    Unavailable(String),
+    Internal(String),
+}
+
+impl RenderError {
+    pub fn code(&self) -> i32 {
+        match self {
+            RenderError::InvalidInput(_) => 400,
+            RenderError::Forbidden(_) => 403,
+            RenderError::Unrenderable(_) => 422,
+            RenderError::Upstream(_) => 502,
+            RenderError::Timeout(_) => 504,
+            RenderError::Cancelled => 499,
+            RenderError::QueueFull => 429,
+            RenderError::Unavailable(_) => 503,
+            RenderError::Internal(_) => 500,
+        }
+    }
+
+    pub fn timeout(phase: &str, timeout: Duration) -> Self {
+        RenderError::Timeout(format!(
+            "deadline exceeded after {}ms during {}",
+            timeout.as_millis(),
+            phase
+        ))
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::InvalidInput(message)
+            | RenderError::Forbidden(message)
+            | RenderError::Unrenderable(message)
+            | RenderError::Upstream(message)
+            | RenderError::Timeout(message)
+            | RenderError::Unavailable(message)
+            | RenderError::Internal(message) => write!(f, "{}", message),
+            RenderError::Cancelled => write!(f, "render cancelled"),
+            RenderError::QueueFull => write!(f, "render queue is full"),
+        }
+    }
+}
+
+impl Error for RenderError {}
+
+// Failing browser calls mean the page could not be rendered
+impl From<anyhow::Error> for RenderError {
+    fn from(err: anyhow::Error) -> Self {
+        RenderError::Unrenderable(err.to_string())
+    }
 }
 
 #[derive(Copy, Clone)]